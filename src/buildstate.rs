@@ -0,0 +1,156 @@
+use crate::compileutils::*;
+use crate::errors::*;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
+
+/// ! What an object was last compiled from
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UnitState {
+    /// ! Hash of the working directory and the full compiler command line
+    pub command: String,
+    /// ! Hash of the source and of every header listed in its depfile
+    pub inputs: BTreeMap<String, String>,
+}
+
+/// ! Persisted as `<output>/knob-state.json`. Objects are rebuilt when the content of their
+/// ! source or headers, or their command line, changed since they were recorded here
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BuildState {
+    units: BTreeMap<String, UnitState>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// ! Hashes computed during this run, headers are shared by many translation units
+    #[serde(skip)]
+    hashes: HashMap<PathBuf, Option<String>>,
+}
+
+impl BuildState {
+    /// ! A missing or unreadable state file just means everything gets rebuilt
+    pub fn load(output: &Path) -> BuildState {
+        let path = output.join("knob-state.json");
+        let mut state: BuildState = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        state.path = path;
+        state
+    }
+
+    pub fn save(&self) -> KnobResult<()> {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&self.path, json).map_err(|e| KnobError::io(&self.path, e))
+    }
+
+    /// ! Whether the object of `command` is missing or was built from different inputs
+    pub fn is_stale(&mut self, command: &CompileCommand) -> bool {
+        if !command.object().exists() {
+            return true;
+        }
+        let unit = match self.units.get(&command.output) {
+            Some(unit) => unit.clone(),
+            None => return true,
+        };
+        if unit.command != hash_command(command) {
+            return true;
+        }
+        unit.inputs
+            .iter()
+            .any(|(path, hash)| self.hash(Path::new(path)).as_ref() != Some(hash))
+    }
+
+    /// ! Records a successful compile using the depfile the compiler just wrote
+    pub fn record(&mut self, command: &CompileCommand) {
+        let directory = PathBuf::from(&command.directory);
+        let headers = fs::read_to_string(depfile(&command.object()))
+            .map(|deps| parse_depfile(&deps))
+            .unwrap_or_default();
+
+        let mut inputs = BTreeMap::new();
+        for input in std::iter::once(command.source()).chain(headers) {
+            let input = directory.join(input);
+            if let Some(hash) = self.hash(&input) {
+                inputs.insert(input.to_str().unwrap().to_string(), hash);
+            }
+        }
+
+        self.units.insert(
+            command.output.clone(),
+            UnitState {
+                command: hash_command(command),
+                inputs,
+            },
+        );
+    }
+
+    fn hash(&mut self, path: &Path) -> Option<String> {
+        self.hashes
+            .entry(path.to_path_buf())
+            .or_insert_with(|| hash_file(path))
+            .clone()
+    }
+}
+
+/// ! Fingerprint of a shared dependency: its build script, the size and modification time of
+/// ! every file under its root, the whole toolchain and the fingerprints of what it requires.
+/// ! File metadata is enough here and keeps large trees like SDL cheap to check
+pub fn dependency_fingerprint(
+    platform: &BuildPlatform,
+    dep: &BuildShared,
+    required: &[String],
+) -> KnobResult<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(dep.build.as_bytes());
+    hasher.update([0]);
+    hasher.update(format!("{:?}", platform).as_bytes());
+    for fingerprint in required {
+        hasher.update([0]);
+        hasher.update(fingerprint.as_bytes());
+    }
+
+    let walker = WalkDir::new(&dep.root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+    for entry in walker {
+        let entry = entry.map_err(|e| KnobError::io(&dep.root, e.into()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .map_err(|e| KnobError::io(entry.path(), e.into()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let relative = entry.path().strip_prefix(&dep.root).unwrap();
+        hasher.update([0]);
+        hasher.update(relative.to_str().unwrap().as_bytes());
+        hasher.update(metadata.len().to_le_bytes());
+        hasher.update(modified.as_nanos().to_le_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// ! Hex encoded SHA-256 of a file's content, `None` when it can't be read
+pub fn hash_file(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    Some(format!("{:x}", Sha256::digest(content)))
+}
+
+fn hash_command(command: &CompileCommand) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(command.directory.as_bytes());
+    for arg in &command.arguments {
+        hasher.update([0]);
+        hasher.update(arg.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
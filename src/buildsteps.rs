@@ -1,94 +1,288 @@
+use crate::buildstate::*;
 use crate::compileutils::*;
+use crate::errors::*;
+use crate::jobs::*;
 use crate::procutils::*;
 use crate::types::*;
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 /// ! Step 1 : Build the libraries and loose objects in `./.deps/`
-/// ! Depends on Bash
-pub fn build_shared(shared: &Vec<BuildShared>, filters: &Vec<String>) {
-    let mut loose_objs = Vec::new();
-    // Build Shared Dependencies
-    for dep in shared {
-        let name = dep.root.file_name().unwrap().to_str().unwrap();
-        if filters.contains(&name.to_string()) {
-            let prev = cwd();
-            cd(&dep.root.clone());
-            execute(
-                "bash",
-                &vec!["-c".to_string(), dep.build.clone()],
-                false,
-                true,
-            )
-            .unwrap();
-            cd(&prev.clone());
-
-            // Archive loose dependencies as libdependencies.a
-            if dep.is_loose() {
-                println!("Built Loose Shared {}", name);
-                loose_objs.append(&mut dep.objs.clone());
-            } else {
-                println!("Built Shared {}", name);
-            }
+/// ! Depends on Bash. A dependency is skipped when its fingerprint matches the stamp in
+/// ! `<output>/<profile>/deps/` and its libraries and objects exist, unless `rebuild` is set.
+/// ! Dependencies that don't require each other are built concurrently and split `jobs` between
+/// ! them, each one logging to `<output>/<profile>/deps/<name>.log`
+pub fn build_shared(
+    root: &Path,
+    platform: &BuildPlatform,
+    shared: &[BuildShared],
+    dirs: &BuildDirs,
+    filters: &[String],
+    rebuild: bool,
+    jobs: usize,
+) -> KnobResult<()> {
+    // Every profile keeps its own, switching profiles doesn't invalidate the other's builds
+    let stamps = dirs.objects.join("deps");
+    mkdir(&stamps)?;
+    // Requirements are built too, `shared` is already in build order
+    let filters = required_dependencies(shared, filters);
+    let selected: Vec<&BuildShared> = shared
+        .iter()
+        .filter(|d| filters.iter().any(|f| f == d.name()))
+        .collect();
+
+    let index: HashMap<&str, usize> = selected
+        .iter()
+        .enumerate()
+        .map(|(i, d)| (d.name(), i))
+        .collect();
+    let requires: Vec<Vec<usize>> = selected
+        .iter()
+        .map(|d| {
+            d.requires
+                .iter()
+                .filter_map(|r| index.get(r.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    let fingerprints: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    let log = |dep: &BuildShared| stamps.join(format!("{}.log", dep.name()));
+    // A dependency starts as soon as those it requires are built
+    let outcomes = run_graph(&requires, jobs, |i, concurrent| {
+        let dep = selected[i];
+        let required: Vec<String> = {
+            let fingerprints = fingerprints.lock().unwrap();
+            dep.requires
+                .iter()
+                .filter_map(|r| fingerprints.get(r).cloned())
+                .collect()
+        };
+        let stamp = stamps.join(format!("{}.stamp", dep.name()));
+        let fingerprint = dependency_fingerprint(platform, dep, &required)?;
+        // What a driver installs isn't declared, it has to have left libraries behind
+        let built = dep.libs.iter().chain(&dep.objs).all(|p| p.exists())
+            && (dep.driver == Driver::Script || !dep.libraries().is_empty());
+        let stamped = fs::read_to_string(&stamp).is_ok_and(|s| s.trim() == fingerprint);
+        if !rebuild && built && stamped {
+            println!("Shared {} is up to date", dep.name());
+            fingerprints
+                .lock()
+                .unwrap()
+                .insert(dep.name().to_string(), fingerprint);
+            return Ok(());
+        }
+        // A failed build must not leave a matching stamp behind
+        rm(&stamp)?;
+        fs::create_dir_all(&dep.prefix).map_err(|e| KnobError::io(&dep.prefix, e))?;
+
+        // Scripts start their own processes, the builds running at once split the job limit
+        let share = (jobs / concurrent).max(1);
+        run_job(&Job {
+            label: format!("Building Shared {}...", dep.name()),
+            binary: "bash".to_string(),
+            args: vec!["-c".to_string(), dep.build.clone()],
+            dir: Some(dep.root.clone()),
+            env: dependency_env(platform, shared, dep, share),
+            log: Some(log(dep)),
+        })?;
+
+        // The build wrote into the dependency, what it left is the state to compare against
+        let fingerprint = dependency_fingerprint(platform, dep, &required)?;
+        fs::write(&stamp, &fingerprint).map_err(|e| KnobError::io(&stamp, e))?;
+        fingerprints
+            .lock()
+            .unwrap()
+            .insert(dep.name().to_string(), fingerprint);
+        if dep.is_loose() {
+            println!("Built Loose Shared {}", dep.name());
+        } else {
+            println!("Built Shared {}", dep.name());
         }
+        Ok(())
+    });
+
+    // Stamps are written for every success, even when another build failed
+    if outcomes.iter().any(|(_, r)| r.is_err()) {
+        eprintln!("Shared dependencies failed:");
+        let mut first = None;
+        let ran: Vec<usize> = outcomes.iter().map(|(i, _)| *i).collect();
+        for (i, failure) in outcomes {
+            let dep = selected[i];
+            let message = match &failure {
+                Err(KnobError::Subprocess { message, .. }) => message.clone(),
+                Err(e) => e.to_string(),
+                Ok(()) => continue,
+            };
+            eprintln!("  {}: {}, see {}", dep.name(), message, log(dep).display());
+            first.get_or_insert(failure);
+        }
+        // Whatever didn't get to start
+        let skipped: Vec<&str> = (0..selected.len())
+            .filter(|i| !ran.contains(i))
+            .map(|i| selected[i].name())
+            .collect();
+        if !skipped.is_empty() {
+            eprintln!("  not built: {}", skipped.join(", "));
+        }
+        return first.unwrap();
     }
 
-    // Archive loose dependencies as libdependencies.a
-    if loose_objs.len() > 0 {
+    // Archive loose dependencies as libdependencies.a, linked from the project root
+    let loose_objs: Vec<PathBuf> = selected
+        .iter()
+        .filter(|d| d.is_loose())
+        .flat_map(|d| d.objs.clone())
+        .collect();
+    if !loose_objs.is_empty() {
         println!("Archiving {} Loose Shared Objects...", loose_objs.len());
-        let mut args = if std::env::consts::OS == "macos" {
-            vec!["-r".to_string(), "libdependencies.a".to_string()]
-        } else {
-            vec!["r".to_string(), "libdependencies.a".to_string()]
-        };
-        args.append(
-            &mut loose_objs
-                .into_iter()
-                .map(|x| (*x.to_str().unwrap()).to_string())
-                .collect(),
-        );
-        execute(&"ar", &args, false, false).unwrap();
+        archive(platform, &root.join("libdependencies.a"), &loose_objs)?;
+    }
+
+    Ok(())
+}
+
+/// ! Environment of the `build` and `clean` scripts of `dep`, so they use the project's toolchain:
+/// ! - `CC`/`CFLAGS` and `CXX`/`CXXFLAGS` from the C and C++ toolchains, `AR`, and `LDFLAGS`
+/// ! - `KNOB_PROFILE`, `KNOB_PLATFORM`, `KNOB_JOBS`, the number of processes the script may run
+/// ! - `KNOB_OUT_DIR`, `<output>/<profile>/deps/<name>/`, the directory the dependency may install into
+/// ! - `KNOB_REQUIRES`, the dependencies it requires directly or not. For SDL, `KNOB_SDL_ROOT`,
+/// !   `KNOB_SDL_INCLUDE`, `KNOB_SDL_LIBRARIES` and `KNOB_SDL_PREFIX` hold its directory, its
+/// !   headers, its space separated libraries and its `KNOB_OUT_DIR`
+pub fn dependency_env(
+    platform: &BuildPlatform,
+    shared: &[BuildShared],
+    dep: &BuildShared,
+    jobs: usize,
+) -> Vec<(String, String)> {
+    let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+    let (cc, cflags) = platform.toolchain(Path::new("dependency.c"));
+    let (cxx, cxxflags) = platform.toolchain(Path::new("dependency.cpp"));
+    let mut ldflags = platform.linker_args.clone();
+    ldflags.append(&mut platform.profile.linker_args.clone());
+
+    let required = required_dependencies(shared, &dep.requires);
+    let mut env = vec![
+        ("CC".to_string(), cc),
+        ("CFLAGS".to_string(), cflags.join(" ")),
+        ("CXX".to_string(), cxx),
+        ("CXXFLAGS".to_string(), cxxflags.join(" ")),
+        ("AR".to_string(), platform.archiver.clone()),
+        ("LDFLAGS".to_string(), ldflags.join(" ")),
+        ("KNOB_PROFILE".to_string(), platform.profile.name.clone()),
+        ("KNOB_PLATFORM".to_string(), platform.name.clone()),
+        ("KNOB_JOBS".to_string(), jobs.to_string()),
+        ("KNOB_OUT_DIR".to_string(), path(&dep.prefix)),
+        ("KNOB_REQUIRES".to_string(), required.join(" ")),
+    ];
+    for required in shared
+        .iter()
+        .filter(|d| required.iter().any(|r| r == d.name()))
+    {
+        let var = |suffix: &str| format!("KNOB_{}_{}", env_name(required.name()), suffix);
+        let libs: Vec<String> = required.libraries().iter().map(path).collect();
+        env.push((var("ROOT"), path(&required.root)));
+        env.push((var("INCLUDE"), path(&required.headers)));
+        env.push((var("LIBRARIES"), libs.join(" ")));
+        env.push((var("PREFIX"), path(&required.prefix)));
     }
+    env
+}
+
+/// ! `imgui-sdl` => `IMGUI_SDL`
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+/// ! Archives objects into a static library with the platform's archiver. The previous archive
+/// ! is removed first so objects that no longer exist don't linger in it
+pub fn archive(platform: &BuildPlatform, library: &Path, objs: &[PathBuf]) -> KnobResult<()> {
+    rm(library)?;
+    // `D` zeroes the timestamps, uids and modes stored in the archive
+    let flags = match (std::env::consts::OS, platform.reproducible) {
+        ("macos", _) => "-rcs",
+        (_, true) => "rcsD",
+        (_, false) => "rcs",
+    };
+    let mut args = vec![flags.to_string(), library.to_str().unwrap().to_string()];
+    args.append(
+        &mut objs
+            .iter()
+            .map(|x| (*x.to_str().unwrap()).to_string())
+            .collect(),
+    );
+    execute(&platform.archiver, &args, false, false)?;
+    Ok(())
 }
 
 /// ! Step 2
-/// ! Compiles only the files in `./src/` and generates objects. Run with `bear` to generate `compile_commands.json`
+/// ! Compiles only the files in `./src/` and generates objects, running up to `jobs` compilers
+/// ! at once. Sources whose content, headers and flags didn't change are skipped. Returns the commands
+/// ! used so they can be written to `compile_commands.json`
+/// ! Only the sources one of `targets` links are compiled, all of them when `targets` is empty
 pub fn compile_project(
-    root: &PathBuf,
+    root: &Path,
     platform: &BuildPlatform,
-    shared: &Vec<BuildShared>,
+    shared: &[BuildShared],
     dirs: &BuildDirs,
-) -> Vec<PathBuf> {
+    targets: &[BuildTarget],
+    state: &mut BuildState,
+    jobs: usize,
+) -> KnobResult<Vec<CompileCommand>> {
     // Compile Project Source
-    let sources = find_sources(&dirs.sources);
+    let mut sources = find_sources(&dirs.sources, &dirs.filter)?;
+    if !targets.is_empty() {
+        sources.retain(|source| {
+            let relative = source.strip_prefix(&dirs.sources).unwrap();
+            targets.iter().any(|t| t.filter.matches(relative))
+        });
+    }
 
-    let mut compiler_args =
-        generate_include_args(root, dirs, shared, &platform.compiler_args.clone());
+    let includes = generate_include_args(root, platform, dirs, shared, &[], &[])?;
 
-    let mut objs = Vec::new();
+    let mut commands = Vec::new();
     for source in sources {
-        let o = compile(&platform.compiler, &source, &dirs.output, &compiler_args);
-        objs.push(o);
+        let (compiler, mut compiler_args) = platform.toolchain(&source);
+        compiler_args.append(&mut includes.clone());
+        let object = object_path(dirs, &source);
+        let command = compile_command(root, &compiler, &source, &object, &compiler_args)?;
+        commands.push(command);
     }
+    // Entrypoints are compiled later, but collide before anything is written all the same
+    let mut planned = commands.clone();
+    for target in targets {
+        planned.extend(entrypoint_command(root, platform, shared, dirs, target)?);
+    }
+    check_collisions(&planned)?;
+    compile_all(&commands, state, jobs)?;
 
-    objs
+    Ok(commands)
 }
 
 /// ! Step 3
-/// !  Links a list of `*.o` into an executables. One of them must include `main()`. Suggested to
-/// append `compile(entrypoint)` to the list of objects
+/// ! Links a list of `*.o` into an executable. One of them must include `main()`, usually the
+/// ! object of the target's entrypoint, along with the dependency libraries
+/// ! Shared library targets are linked with `-shared` and their soname/install_name instead
 pub fn link_binary(
-    root: &PathBuf,
+    root: &Path,
     platform: &BuildPlatform,
-    shared: &Vec<BuildShared>,
+    shared: &[BuildShared],
     target: &BuildTarget,
-    objs: &Vec<PathBuf>,
-) {
+    objs: &[PathBuf],
+) -> KnobResult<()> {
     // Enter the directory of the Project root before linking
-    let prev = cwd();
-    cd(&root.clone());
+    let prev = cwd()?;
+    cd(root)?;
     // Output binary path
-    let mut binary = root.clone();
-    binary.push(&target.name);
+    let mut binary = root.to_path_buf();
+    binary.push(target.artifact());
 
     let mut objs: Vec<String> = objs
         .iter()
@@ -99,7 +293,7 @@ pub fn link_binary(
     let mut libraries = Vec::new();
     for d in shared {
         if !d.is_loose() {
-            libraries.append(&mut d.libs.clone());
+            libraries.append(&mut d.libraries());
         } else {
             needs_to_link_libdeps = true;
         }
@@ -107,11 +301,19 @@ pub fn link_binary(
     println!(
         "Linking {} Modules => {}",
         objs.len() + libraries.len(),
-        target.name.clone()
+        target.artifact()
     );
 
     // Specify binary name
     let mut args = vec!["-o".to_string(), binary.to_str().unwrap().to_string()];
+    if target.kind == TargetKind::Shared {
+        args.push("-shared".to_string());
+        match std::env::consts::OS {
+            "macos" => args.push(format!("-Wl,-install_name,@rpath/{}", target.artifact())),
+            "windows" => {}
+            _ => args.push(format!("-Wl,-soname,{}", target.artifact())),
+        }
+    }
     // Generate -L paths for dependencies
     args.append(&mut generate_library_args(&libraries));
     // Link loose dependencies
@@ -121,12 +323,92 @@ pub fn link_binary(
     }
     // Add Project.toml defined arguments
     args.append(&mut platform.linker_args.clone());
+    args.append(&mut platform.profile.linker_args.clone());
     args.append(&mut target.linker_args.clone());
     // Compiled project objects
     args.append(&mut objs);
+    // After the objects that use them, the order matters for static libraries
+    for package in &platform.system {
+        args.append(&mut package.libs.clone());
+    }
 
-    execute(&platform.linker, &args, true, true).unwrap();
+    let linked = execute(&platform.linker, &args, true, true);
 
     // Exit the project directory
-    cd(&prev.clone());
+    cd(&prev.clone())?;
+    linked.map(|_| ())
+}
+
+/// ! Compiles the entrypoint of `target` and links or archives it with the already compiled
+/// ! project objects its filter lets through. Returns the command that compiled the
+/// ! entrypoint, if the target has one
+pub fn build_target(
+    root: &Path,
+    platform: &BuildPlatform,
+    shared: &[BuildShared],
+    dirs: &BuildDirs,
+    target: &BuildTarget,
+    project: &[CompileCommand],
+    state: &mut BuildState,
+) -> KnobResult<Option<CompileCommand>> {
+    let mut objs: Vec<PathBuf> = project
+        .iter()
+        .filter(|c| match c.source().strip_prefix(&dirs.sources) {
+            Ok(relative) => target.filter.matches(relative),
+            Err(_) => true,
+        })
+        .map(|c| c.object())
+        .collect();
+    let entrypoint = entrypoint_command(root, platform, shared, dirs, target)?;
+    if let Some(command) = &entrypoint {
+        println!("Compiling {}...", target.name);
+        compile_all(std::slice::from_ref(command), state, 1)?;
+        objs.push(command.object());
+    }
+
+    if target.kind == TargetKind::Static {
+        println!("Archiving {} Objects => {}", objs.len(), target.artifact());
+        archive(platform, &root.join(target.artifact()), &objs)?;
+    } else {
+        println!("Linking Binary...");
+        link_binary(root, platform, shared, target, &objs)?;
+    }
+
+    Ok(entrypoint)
+}
+
+/// ! Command compiling the entrypoint of `target` with its own arguments and include paths
+pub fn entrypoint_command(
+    root: &Path,
+    platform: &BuildPlatform,
+    shared: &[BuildShared],
+    dirs: &BuildDirs,
+    target: &BuildTarget,
+) -> KnobResult<Option<CompileCommand>> {
+    let object = entrypoint_object_path(root, dirs, target);
+    match (&target.entrypoint, object) {
+        (Some(entrypoint), Some(object)) => {
+            let (compiler, mut args) = platform.toolchain(entrypoint);
+            args.append(&mut target.compiler_args.clone());
+            let args =
+                generate_include_args(root, platform, dirs, shared, &target.includes, &args)?;
+            Ok(Some(compile_command(
+                root, &compiler, entrypoint, &object, &args,
+            )?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// ! Union of the shared dependencies used by `targets`
+pub fn target_dependencies(targets: &[BuildTarget]) -> Vec<String> {
+    let mut deps: Vec<String> = Vec::new();
+    for target in targets {
+        for dep in &target.dependencies {
+            if !deps.contains(dep) {
+                deps.push(dep.clone());
+            }
+        }
+    }
+    deps
 }
@@ -1,280 +1,775 @@
+use crate::buildstate::*;
+use crate::config::*;
+use crate::drivers::*;
+use crate::errors::*;
+use crate::jobs::*;
 use crate::procutils::*;
+use crate::system::*;
 use crate::types::*;
-use std::collections::HashSet;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use toml::Table;
-use toml::Value;
+use std::sync::Mutex;
 use walkdir::WalkDir;
 
-/// ! Parse the .deps folder
-pub fn parse_dependencies(file: &Path) -> Vec<BuildShared> {
+/// ! Names of the `[Platform.<os>]` tables knob knows about
+pub const PLATFORMS: [&str; 3] = ["linux", "osx", "win32"];
+
+/// ! Parse the .deps folder, drivers install under `<objects>/deps/`
+pub fn parse_dependencies(file: &Path, objects: &Path) -> KnobResult<Vec<BuildShared>> {
     let mut ret = Vec::new();
-    for entry in WalkDir::new(file).max_depth(1) {
+    for entry in WalkDir::new(file).max_depth(1).sort_by_file_name() {
         if let Ok(entry) = &entry {
             let entry = entry.clone().into_path();
 
             // Skip over the self reference, for some reason it is included in walkdir
-            if entry.clone().into_boxed_path() == file.into() {
+            if entry.clone().into_boxed_path() == file.into() || !entry.is_dir() {
+                continue;
+            }
+            // `knob fetch` works in hidden directories
+            if entry
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with('.')
+            {
                 continue;
             }
 
-            // Change directory into the current dependency folder
-            let prev = cwd();
-            cd(&entry.clone());
-
-            let mut dep = entry.clone();
-            dep.push("Dependency.toml");
-            let dep = dep.as_path();
-            let dep = fs::read_to_string(dep).unwrap();
-            let dep = dep.parse::<Table>().unwrap();
-
-            let libs = {
-                if let Some(libs) = dep.get("Libraries") {
-                    libs.as_table()
-                        .unwrap()
-                        .iter()
-                        .fold(Vec::new(), |mut acc, (x, y)| {
-                            let mut path = cwd();
-                            path.push(y.as_str().unwrap());
-                            acc.push(path);
-                            acc
-                        })
-                } else {
-                    Vec::new()
+            let mut path = entry.clone();
+            path.push("Dependency.toml");
+            let dep: DependencyConfig = read_config(&path)?;
+
+            let build = driver_script(&path, &dep)?;
+            let prefix = objects
+                .join("deps")
+                .join(entry.file_name().unwrap().to_str().unwrap());
+            let libs = dep.libraries.values().map(|lib| entry.join(lib)).collect();
+            let objs = dep.objects.values().map(|obj| entry.join(obj)).collect();
+            // Installed headers only exist once the driver ran
+            let headers = match (&dep.headers, dep.driver) {
+                (Some(headers), _) => entry.join(headers),
+                (None, Driver::Script) => {
+                    return Err(KnobError::MissingKey {
+                        file: path,
+                        key: "headers".to_string(),
+                    })
                 }
+                (None, _) => prefix.join("include"),
             };
-            let objs = {
-                if let Some(objs) = dep.get("Objects") {
-                    objs.as_table()
-                        .unwrap()
-                        .iter()
-                        .fold(Vec::new(), |mut acc, (x, y)| {
-                            let mut path = cwd();
-                            path.push(y.as_str().unwrap());
-                            acc.push(path);
-                            acc
-                        })
-                } else {
-                    Vec::new()
-                }
-            };
-            let mut headers = entry.clone().to_path_buf();
-            headers.push(dep.get("headers").unwrap().as_str().unwrap());
+            if dep.headers.is_some() && !headers.is_dir() {
+                return Err(KnobError::MissingPath { path: headers });
+            }
 
             ret.push(BuildShared {
                 root: entry.clone(),
-                clean: dep.get("clean").unwrap().as_str().unwrap().to_string(),
-                build: dep.get("build").unwrap().as_str().unwrap().to_string(),
+                clean: dep.clean,
+                build,
                 headers: headers.clone(),
                 objs,
                 libs,
+                requires: dep.requires,
+                driver: dep.driver,
+                prefix,
             });
+        }
+    }
+
+    order_dependencies(ret)
+}
 
-            cd(&prev.clone());
+/// ! Sorts dependencies so that every one comes after those it `requires`, keeping the directory
+/// ! order otherwise. Unknown requirements and cycles are reported against the Dependency.toml
+/// ! declaring them
+pub fn order_dependencies(shared: Vec<BuildShared>) -> KnobResult<Vec<BuildShared>> {
+    let config = |dep: &BuildShared, message: String| KnobError::Config {
+        file: dep.root.join("Dependency.toml"),
+        message,
+    };
+    for dep in &shared {
+        for required in &dep.requires {
+            if !shared.iter().any(|d| d.name() == required) {
+                return Err(config(
+                    dep,
+                    format!("requires `{}` which isn't a dependency", required),
+                ));
+            }
         }
     }
 
-    ret
+    // Depth first, `visiting` holds the current path to report cycles
+    fn visit<'a>(
+        dep: &'a BuildShared,
+        shared: &'a [BuildShared],
+        visiting: &mut Vec<&'a str>,
+        ordered: &mut Vec<&'a BuildShared>,
+    ) -> Result<(), Vec<String>> {
+        if ordered.iter().any(|d| d.name() == dep.name()) {
+            return Ok(());
+        }
+        if let Some(start) = visiting.iter().position(|n| *n == dep.name()) {
+            let mut cycle: Vec<String> = visiting[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(dep.name().to_string());
+            return Err(cycle);
+        }
+        visiting.push(dep.name());
+        for required in &dep.requires {
+            let required = shared.iter().find(|d| d.name() == required).unwrap();
+            visit(required, shared, visiting, ordered)?;
+        }
+        visiting.pop();
+        ordered.push(dep);
+        Ok(())
+    }
+
+    let mut ordered = Vec::new();
+    for dep in &shared {
+        if let Err(cycle) = visit(dep, &shared, &mut Vec::new(), &mut ordered) {
+            return Err(config(
+                dep,
+                format!("dependency cycle {}", cycle.join(" -> ")),
+            ));
+        }
+    }
+    Ok(ordered.into_iter().cloned().collect())
 }
-pub fn parse_toml(file: &Path) -> (BuildDirs, BuildPlatform, Vec<BuildShared>, BuildTarget) {
-    let prev = cwd();
+
+/// ! `names` and everything they require, directly or not
+pub fn required_dependencies(shared: &[BuildShared], names: &[String]) -> Vec<String> {
+    let mut required: Vec<String> = names.to_vec();
+    let mut i = 0;
+    while i < required.len() {
+        if let Some(dep) = shared.iter().find(|d| d.name() == required[i]) {
+            for name in &dep.requires {
+                if !required.contains(name) {
+                    required.push(name.clone());
+                }
+            }
+        }
+        i += 1;
+    }
+    required
+}
+/// ! Parses Project.toml for the build profile called `profile`
+pub fn parse_toml(
+    file: &Path,
+    profile: &str,
+) -> KnobResult<(BuildDirs, BuildPlatform, Vec<BuildShared>, Vec<BuildTarget>)> {
+    let prev = cwd()?;
     let parent = file.parent().unwrap();
-    cd(&parent.to_path_buf());
-    let toml = fs::read_to_string(file).unwrap();
-    let toml = toml.parse::<Table>().unwrap();
+    cd(parent)?;
+    // Always return to the previous directory, even when the config is invalid
+    let project = parse_project(file, profile);
+    cd(&prev)?;
+    project
+}
 
-    // Extract I/O folders
-    let output = toml.get("output").unwrap().as_str().unwrap();
-    let dependencies = toml.get("dependencies").unwrap().as_str().unwrap();
-    let sources = toml.get("sources").unwrap().as_str().unwrap();
+fn parse_project(
+    file: &Path,
+    profile: &str,
+) -> KnobResult<(BuildDirs, BuildPlatform, Vec<BuildShared>, Vec<BuildTarget>)> {
+    let toml: ProjectConfig = read_config(file)?;
 
     // Make Directory if it doesn't Exist
-    mkdir(&PathBuf::from(output));
-    mkdir(&PathBuf::from(dependencies));
-    mkdir(&PathBuf::from(sources));
+    mkdir(&PathBuf::from(&toml.output))?;
+    mkdir(&PathBuf::from(&toml.dependencies))?;
+    mkdir(&PathBuf::from(&toml.sources))?;
 
     // Format to absolute paths
-    let build = canonicalize(output);
+    let build = canonicalize(&toml.output)?;
     println!("Output Directory: {:?}", build);
-    let deps = canonicalize(dependencies);
+    let deps = canonicalize(&toml.dependencies)?;
     println!("Dependency Directory: {:?}", deps);
-    let src = canonicalize(sources);
+    let src = canonicalize(&toml.sources)?;
     println!("Sources Directory: {:?}", src);
 
+    // Every profile keeps its objects apart so they never overwrite each other
+    let mut profiles = ProfileConfig::builtin();
+    profiles.extend(toml.profiles);
+    let profile = match profiles.get(profile) {
+        Some(config) => BuildProfile {
+            name: profile.to_string(),
+            compiler_args: config.compiler_args.clone(),
+            linker_args: config.linker_args.clone(),
+        },
+        None => {
+            return Err(KnobError::Config {
+                file: file.to_path_buf(),
+                message: format!(
+                    "no [Profile.{}], expected one of {:?}",
+                    profile,
+                    profiles.keys().collect::<Vec<_>>()
+                ),
+            })
+        }
+    };
+    let objects = build.join(&profile.name);
+    mkdir(&objects)?;
+    println!("Profile: {}", profile.name);
+
     // Select Platform Depending on Host
-    let platforms = toml.get("Platform").unwrap().as_table().unwrap();
-    let platform = {
+    let os = {
         if env::consts::OS == "macos" {
-            platforms.get("osx").unwrap().as_table().unwrap()
+            "osx"
         } else if env::consts::OS == "windows" {
-            platforms.get("win32").unwrap().as_table().unwrap()
+            "win32"
         } else if env::consts::OS == "linux" {
-            platforms.get("linux").unwrap().as_table().unwrap()
+            "linux"
         } else {
-            panic!("wtf");
+            return Err(KnobError::Config {
+                file: file.to_path_buf(),
+                message: format!("unsupported host platform {}", env::consts::OS),
+            });
         }
     };
-
-    let platform = BuildPlatform {
-        compiler: platform
-            .get("compiler")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        linker: platform
-            .get("linker")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-        compiler_args: platform
-            .get("compiler_args")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| x.as_str().unwrap().to_string())
-            .collect(),
-        linker_args: platform
-            .get("linker_args")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| x.as_str().unwrap().to_string())
-            .collect(),
+    let platform = match toml.platforms.get(os) {
+        Some(platform) => platform.clone(),
+        None => {
+            return Err(KnobError::MissingKey {
+                file: file.to_path_buf(),
+                key: format!("Platform.{}", os),
+            })
+        }
     };
 
-    // Extract dependencies
-    let dependencies = parse_dependencies(&*deps);
+    let toolchains = platform
+        .toolchains()
+        .into_iter()
+        .map(|(language, toolchain)| {
+            let toolchain = BuildToolchain {
+                compiler: toolchain
+                    .compiler
+                    .clone()
+                    .unwrap_or_else(|| platform.compiler.clone()),
+                compiler_args: toolchain.compiler_args.clone(),
+            };
+            (language, toolchain)
+        })
+        .collect();
 
-    // Extract the host platform's build targets
-    let targets = toml.get("Target").unwrap().as_table().unwrap();
+    let platform_excludes = platform_excludes(os, &platform);
+    let system = parse_system(file, os, &platform, &toml.system)?;
 
-    // Parse Project
+    let mut compiler_args = platform.compiler_args;
+    // Project objects are shared between all targets, so they all have to be position
+    // independent as soon as one target is a shared library
+    let has_shared = toml.targets.values().any(|t| t.kind == TargetKind::Shared);
+    if has_shared && env::consts::OS != "windows" {
+        compiler_args.push("-fPIC".to_string());
+    }
 
-    // Parse Targets
-    let targets: Vec<BuildTarget> = targets
-        .iter()
-        .map(|(name, toml)| {
-            let entrypoint = toml.get("entrypoint").unwrap().as_str().unwrap();
-            let entrypoint = canonicalize(entrypoint);
+    if toml.reproducible {
+        reproducible_args(file, &mut compiler_args)?;
+    }
 
-            let compiler_args = toml
-                .get("compiler_args")
-                .unwrap()
-                .as_array()
-                .unwrap()
-                .into_iter()
-                .map(|x| x.as_str().unwrap().to_string())
-                .collect::<Vec<String>>();
-            let linker_args = toml
-                .get("linker_args")
-                .unwrap()
-                .as_array()
-                .unwrap()
-                .into_iter()
-                .map(|x| x.as_str().unwrap().to_string())
-                .collect::<Vec<String>>();
+    let platform = BuildPlatform {
+        name: os.to_string(),
+        reproducible: toml.reproducible,
+        toolchains,
+        compiler: platform.compiler,
+        linker: platform.linker,
+        archiver: platform.archiver,
+        compiler_args,
+        linker_args: platform.linker_args,
+        profile,
+        system,
+    };
 
-            let dependencies = toml
-                .get("deps")
-                .unwrap()
-                .as_array()
-                .unwrap()
-                .into_iter()
-                .map(|x| x.as_str().unwrap().to_string())
-                .collect();
-
-            BuildTarget {
-                entrypoint,
-                dependencies,
-                name: name.to_string(),
-                compiler_args: compiler_args.clone(),
-                linker_args: linker_args.clone(),
+    // Extract dependencies
+    let dependencies = parse_dependencies(&deps, &objects)?;
+    for name in toml.fetch.keys() {
+        if !deps.join(name).exists() {
+            eprintln!(
+                "warning: [Dependency.{}] isn't fetched, run `knob fetch`",
+                name
+            );
+        }
+    }
+
+    // Parse Targets
+    let mut targets = Vec::new();
+    for (name, target) in toml.targets {
+        let entrypoint = match target.entrypoint {
+            Some(entrypoint) => Some(canonicalize(&entrypoint)?),
+            None if target.kind == TargetKind::Executable => {
+                return Err(KnobError::MissingKey {
+                    file: file.to_path_buf(),
+                    key: format!("Target.{}.entrypoint", name),
+                })
             }
-        })
-        .collect();
+            None => None,
+        };
+        let filter = source_filter(
+            file,
+            &format!("Target.{}", name),
+            &target.include,
+            &target.exclude,
+        )?;
+        let includes = include_dirs(&target.include_dirs)?;
+        targets.push(BuildTarget {
+            includes,
+            filter,
+            entrypoint,
+            dependencies: target.dependencies,
+            output_name: target.output_name.unwrap_or_else(|| name.clone()),
+            name,
+            kind: target.kind,
+            compiler_args: target.compiler_args,
+            linker_args: target.linker_args,
+        });
+    }
 
-    if targets.len() > 1 {
-        panic!("Only 1 Build Target is Supported at a Time");
+    // Patterns of .knobignore are added to the project's excludes
+    let knobignore = src.join(".knobignore");
+    let mut exclude = toml.exclude;
+    if knobignore.exists() {
+        let ignored = fs::read_to_string(&knobignore).map_err(|e| KnobError::io(&knobignore, e))?;
+        exclude.extend(
+            ignored
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| l.to_string()),
+        );
     }
+    exclude.extend(platform_excludes);
+    let filter = source_filter(file, "", &toml.include, &exclude)?;
 
-    cd(&prev.clone());
-    (
+    Ok((
         BuildDirs {
             dependencies: deps,
             sources: src,
             output: build,
+            objects,
+            filter,
+            includes: include_dirs(&toml.include_dirs)?,
+            discover_includes: toml.discover_includes,
+            ambiguous_headers: toml.ambiguous_headers,
         },
         platform,
         dependencies,
-        targets[0].clone(),
+        targets,
+    ))
+}
+
+/// ! Maps the project directory to `.` in debug info and `__FILE__`, and makes sure
+/// ! `SOURCE_DATE_EPOCH` is set so `__DATE__`/`__TIME__` are the same on every machine
+fn reproducible_args(file: &Path, compiler_args: &mut Vec<String>) -> KnobResult<()> {
+    let project = cwd()?;
+    let project = project.to_str().unwrap();
+    compiler_args.push(format!("-ffile-prefix-map={}=.", project));
+    compiler_args.push(format!("-fdebug-prefix-map={}=.", project));
+
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) if epoch.parse::<u64>().is_ok() => {}
+        Ok(epoch) => {
+            return Err(KnobError::Config {
+                file: file.to_path_buf(),
+                message: format!("SOURCE_DATE_EPOCH=`{}` isn't a unix timestamp", epoch),
+            })
+        }
+        // Inherited by every compiler, archiver and build script started from now on
+        Err(_) => env::set_var("SOURCE_DATE_EPOCH", "0"),
+    }
+    // Apple's ar has no `D` modifier and reads this instead
+    if env::consts::OS == "macos" {
+        env::set_var("ZERO_AR_DATE", "1");
+    }
+    Ok(())
+}
+
+/// ! Resolves `include_dirs` against the current directory, which is the one of Project.toml
+fn include_dirs(dirs: &[String]) -> KnobResult<Vec<PathBuf>> {
+    dirs.iter().map(|dir| canonicalize(dir)).collect()
+}
+
+/// ! Sources meant for the other platforms: `*_<os>.*`, `*_<os>/` and `platform/<os>/`. Objective-C
+/// ! is only compiled on osx unless the platform declares a toolchain for it
+fn platform_excludes(os: &str, platform: &PlatformConfig) -> Vec<String> {
+    let mut excludes = Vec::new();
+    for other in PLATFORMS.iter().filter(|p| **p != os) {
+        excludes.push(format!("*_{}.*", other));
+        excludes.push(format!("*_{}", other));
+        excludes.push(format!("**/platform/{}", other));
+    }
+    if os != "osx" && platform.objc.is_none() {
+        excludes.push("*.m".to_string());
+    }
+    if os != "osx" && platform.objcpp.is_none() {
+        excludes.push("*.mm".to_string());
+    }
+    excludes
+}
+
+/// ! Compiles `include` and `exclude` into a `SourceFilter`, `table` is the TOML table holding
+/// ! them for error messages
+pub fn source_filter(
+    file: &Path,
+    table: &str,
+    include: &[String],
+    exclude: &[String],
+) -> KnobResult<SourceFilter> {
+    let include = match include {
+        [] => None,
+        include => Some(glob_set(file, &table_key(table, "include"), include)?),
+    };
+    let exclude = glob_set(file, &table_key(table, "exclude"), exclude)?;
+    Ok(SourceFilter { include, exclude })
+}
+
+fn table_key(table: &str, key: &str) -> String {
+    if table.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", table, key)
+    }
+}
+
+fn glob_set(file: &Path, key: &str, patterns: &[String]) -> KnobResult<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        // Like .gitignore, `/` anchors a pattern and a trailing `/` is only there for readability
+        let trimmed = pattern.trim_start_matches("./").trim_end_matches('/');
+        let glob = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if !trimmed.contains('/') => format!("**/{}", trimmed),
+            None => trimmed.to_string(),
+        };
+        let glob = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| KnobError::InvalidValue {
+                file: file.to_path_buf(),
+                key: key.to_string(),
+                message: format!("`{}`: {}", pattern, e.kind()),
+            })?;
+        set.add(glob);
+    }
+    set.build().map_err(|e| KnobError::InvalidValue {
+        file: file.to_path_buf(),
+        key: key.to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// ! Picks the targets to build, either the one called `name`, every target with `all`, or the
+/// ! only target when the project has just one
+pub fn select_targets(
+    file: &Path,
+    targets: &[BuildTarget],
+    name: Option<&str>,
+    all: bool,
+) -> KnobResult<Vec<BuildTarget>> {
+    if all {
+        return Ok(targets.to_vec());
+    }
+    let config = |message: String| KnobError::Config {
+        file: file.to_path_buf(),
+        message,
+    };
+    match name {
+        Some(name) => match targets.iter().find(|t| t.name == name) {
+            Some(target) => Ok(vec![target.clone()]),
+            None => Err(config(format!(
+                "no [Target.{}], expected one of {:?}",
+                name,
+                targets.iter().map(|t| &t.name).collect::<Vec<_>>()
+            ))),
+        },
+        None => match targets {
+            [target] => Ok(vec![target.clone()]),
+            [] => Err(config("no [Target] is declared".to_string())),
+            _ => Err(config(format!(
+                "{} targets are declared, pick one with `knob build <TARGET>` or use --all",
+                targets.len()
+            ))),
+        },
+    }
+}
+
+/// ! Runs every command whose object is stale according to `state`, up to `jobs` at once.
+/// ! Successful compiles are recorded and the state is saved even when one of them failed
+pub fn compile_all(
+    commands: &[CompileCommand],
+    state: &mut BuildState,
+    jobs: usize,
+) -> KnobResult<()> {
+    let stale: Vec<&CompileCommand> = commands.iter().filter(|c| state.is_stale(c)).collect();
+    let queue = stale.iter().map(|c| Job::from(*c)).collect();
+
+    let compiled = {
+        let recorded = Mutex::new(&mut *state);
+        run_jobs_with(queue, jobs, |i, result| {
+            if result.is_ok() {
+                recorded.lock().unwrap().record(stale[i])
+            }
+        })
+    };
+    state.save()?;
+    compiled
+}
+
+/// ! Object of a project source, mirroring its path under `sources` in the profile's objects
+/// ! directory: `src/api/window.cpp` => `build/debug/api/window.cpp.o`
+pub fn object_path(dirs: &BuildDirs, source: &Path) -> PathBuf {
+    dirs.objects.join(mirror(source, &dirs.sources))
+}
+
+/// ! Object of a target's entrypoint, kept apart per target since targets can compile the same
+/// ! entrypoint with different arguments: `build/debug/targets/game/bins/main.cpp.o`
+pub fn entrypoint_object_path(
+    root: &Path,
+    dirs: &BuildDirs,
+    target: &BuildTarget,
+) -> Option<PathBuf> {
+    let entrypoint = target.entrypoint.as_ref()?;
+    Some(
+        dirs.objects
+            .join("targets")
+            .join(&target.name)
+            .join(mirror(entrypoint, root)),
     )
 }
 
-/// ! Returns the path of the output object file
-pub fn compile(compiler: &str, source: &PathBuf, build: &PathBuf, args: &Vec<String>) -> PathBuf {
-    let mut output = build.clone();
-    println!(
-        "Compiling {}...",
-        source.file_name().unwrap().to_str().unwrap()
-    );
-    let module = source.to_str().unwrap().to_string().replace("/", ".") + ".o";
-    output.push(module);
-    let mut _args = vec!["-c".to_string(), source.to_str().unwrap().to_string()];
-    _args.append(&mut args.clone());
+/// ! `source` relative to `base` with `.o` appended, sources outside of `base` keep their
+/// ! whole path without the root
+fn mirror(source: &Path, base: &Path) -> PathBuf {
+    let relative: PathBuf = match source.strip_prefix(base) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => source
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect(),
+    };
+    let mut object = relative.into_os_string();
+    object.push(".o");
+    PathBuf::from(object)
+}
+
+/// ! Fails when two commands write the same object. On macOS and Windows paths are compared
+/// ! ignoring case since `Window.cpp` and `window.cpp` share an object on their file systems,
+/// ! elsewhere such pairs are only a portability warning
+pub fn check_collisions(commands: &[CompileCommand]) -> KnobResult<()> {
+    let case_insensitive = matches!(env::consts::OS, "macos" | "windows");
+    let mut objects: HashMap<String, &CompileCommand> = HashMap::new();
+    let mut folded: HashMap<String, &CompileCommand> = HashMap::new();
+    for command in commands {
+        let key = match case_insensitive {
+            true => command.output.to_lowercase(),
+            false => command.output.clone(),
+        };
+        if let Some(other) = objects.insert(key, command) {
+            return Err(KnobError::Collision {
+                object: command.object(),
+                sources: (other.source(), command.source()),
+            });
+        }
+        if let Some(other) = folded.insert(command.output.to_lowercase(), command) {
+            eprintln!(
+                "warning: {} and {} only differ in case, they would share an object on macOS and Windows",
+                other.source().display(),
+                command.source().display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// ! Returns the compile_commands.json entry that compiles `source` into `object`. It runs in
+/// ! `root` so the directory recorded in objects is the same wherever knob is started from
+pub fn compile_command(
+    root: &Path,
+    compiler: &str,
+    source: &Path,
+    object: &Path,
+    args: &[String],
+) -> KnobResult<CompileCommand> {
+    let output = object.to_path_buf();
+    let parent = output.parent().unwrap();
+    fs::create_dir_all(parent).map_err(|e| KnobError::io(parent, e))?;
+    let mut _args = vec![
+        compiler.to_string(),
+        "-c".to_string(),
+        source.to_str().unwrap().to_string(),
+    ];
+    _args.append(&mut args.to_vec());
     _args.append(&mut vec![
         "-o".to_string(),
         output.to_str().unwrap().to_string(),
     ]);
+    // Have the compiler list every header it read next to the object
+    _args.append(&mut vec![
+        "-MD".to_string(),
+        "-MF".to_string(),
+        depfile(&output).to_str().unwrap().to_string(),
+    ]);
 
-    execute(compiler, &_args, true, true).unwrap();
-    return output;
+    Ok(CompileCommand {
+        directory: root.to_str().unwrap().to_string(),
+        arguments: _args,
+        file: source.to_str().unwrap().to_string(),
+        output: output.to_str().unwrap().to_string(),
+    })
 }
 
-/// ! Recursively searches for files ending in .c/.cpp/c++/.mm
-pub fn find_sources(path: &Path) -> Vec<PathBuf> {
-    let mut sources: Vec<PathBuf> = Vec::new();
+/// ! Writes the clang JSON compilation database used by clangd
+pub fn write_compile_commands(path: &Path, commands: &[CompileCommand]) -> KnobResult<()> {
+    let json = serde_json::to_string_pretty(&CompileCommands(commands.to_vec())).unwrap();
+    fs::write(path, json).map_err(|e| KnobError::io(path, e))
+}
 
-    for entry in WalkDir::new(path) {
-        let path = entry.unwrap();
-        let path = path.path().to_path_buf();
+/// ! Path of the `-MD -MF` depfile written next to an object
+pub fn depfile(object: &Path) -> PathBuf {
+    object.with_extension("d")
+}
 
-        match path.extension() {
-            Some(x) => {
-                if x == ("c") || x == ("cpp") || x == ("c++") || x == "mm" {
-                    sources.push(path.clone());
+/// ! Parses a Makefile style depfile as emitted by `-MD -MF` into its prerequisites
+/// ! Example:
+/// ! build/main.o: src/main.cpp src/api/utils.h \
+/// !  src/my\ file.h
+/// ! - src/main.cpp
+/// ! - src/api/utils.h
+/// ! - src/my file.h
+pub fn parse_depfile(contents: &str) -> Vec<PathBuf> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                // Escaped space inside of a path
+                Some(' ') => {
+                    token.push(' ');
+                    chars.next();
+                }
+                // Line continuation
+                Some('\n') | Some('\r') => {
+                    chars.next();
+                    if !token.is_empty() {
+                        tokens.push(std::mem::take(&mut token));
+                    }
                 }
+                _ => token.push(c),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                token.push('$');
+                chars.next();
             }
-            _ => {}
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    // Everything that isn't a rule target is a prerequisite
+    tokens
+        .into_iter()
+        .filter(|t| !t.ends_with(':'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// ! Recursively searches for files ending in .c/.cpp/.c++/.cc/.cxx/.m/.mm/.s/.S that `filter`
+/// ! lets through
+pub fn find_sources(path: &Path, filter: &SourceFilter) -> KnobResult<Vec<PathBuf>> {
+    let mut sources: Vec<PathBuf> = Vec::new();
+
+    for entry in walk_filtered(path, filter) {
+        let entry = entry.map_err(|e| walk_error(path, e))?;
+        let relative = entry.path().strip_prefix(path).unwrap();
+
+        if entry.file_type().is_file()
+            && Language::from_path(relative).is_some()
+            && filter.matches(relative)
+        {
+            sources.push(entry.path().to_path_buf());
         }
     }
 
-    sources
+    Ok(sources)
 }
-/// ! Recursively searches for files ending in .h/.hpp/.h++
-pub fn find_headers(path: &PathBuf) -> Vec<PathBuf> {
+/// ! Recursively searches for files ending in .h/.hpp/.h++ outside of excluded paths
+pub fn find_headers(path: &Path, filter: &SourceFilter) -> KnobResult<Vec<PathBuf>> {
     let mut sources: Vec<PathBuf> = Vec::new();
 
-    for entry in WalkDir::new(path) {
-        let path = entry.unwrap();
+    for entry in walk_filtered(path, filter) {
+        let path = entry.map_err(|e| walk_error(path, e))?;
         let path = path.path().to_path_buf();
 
-        match path.extension() {
-            Some(x) => {
-                if x == ("h") || x == ("hpp") || x == ("h++") {
-                    sources.push(path.clone());
-                }
+        if let Some(x) = path.extension() {
+            if x == ("h") || x == ("hpp") || x == ("h++") {
+                sources.push(path.clone());
             }
-            _ => {}
         }
     }
 
-    sources
+    Ok(sources)
+}
+
+/// ! Walks `root` without descending into excluded directories
+fn walk_filtered<'a>(
+    root: &'a Path,
+    filter: &'a SourceFilter,
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + 'a {
+    WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(move |e| !filter.excludes(e.path().strip_prefix(root).unwrap()))
+}
+
+/// ! Looks for headers with the same file name in different directories when include paths are
+/// ! discovered, warning with every candidate or failing depending on `ambiguous_headers`
+pub fn check_ambiguous_headers(file: &Path, dirs: &BuildDirs) -> KnobResult<()> {
+    if !dirs.discover_includes {
+        return Ok(());
+    }
+    let mut names: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for header in find_headers(&dirs.sources, &dirs.filter)? {
+        let name = header.file_name().unwrap().to_str().unwrap().to_string();
+        names.entry(name).or_default().push(header);
+    }
+
+    let mut ambiguous = Vec::new();
+    for (name, mut candidates) in names {
+        if candidates.len() < 2 {
+            continue;
+        }
+        candidates.sort();
+        let candidates: Vec<String> = candidates.iter().map(|c| c.display().to_string()).collect();
+        ambiguous.push(format!(
+            "`{}` is ambiguous: {}",
+            name,
+            candidates.join(", ")
+        ));
+    }
+
+    match dirs.ambiguous_headers {
+        AmbiguousHeaders::Warn => {
+            for message in ambiguous {
+                eprintln!("warning: {}", message);
+            }
+            Ok(())
+        }
+        AmbiguousHeaders::Error if ambiguous.is_empty() => Ok(()),
+        AmbiguousHeaders::Error => Err(KnobError::Config {
+            file: file.to_path_buf(),
+            message: ambiguous.join("\n"),
+        }),
+    }
+}
+
+fn walk_error(root: &Path, e: walkdir::Error) -> KnobError {
+    let path = e.path().unwrap_or(root).to_path_buf();
+    KnobError::io(path, e.into())
 }
 
 /// ! Recursively searches for files ending in .h/.hpp/.gpp and generate
@@ -300,7 +795,7 @@ pub fn generate_include_paths(root: &Path, headers: Vec<PathBuf>) -> HashSet<Pat
 
 /// ! Given a list of library files genreate the -L argument for linking
 /// ! Keep in mind the library still must be specified per target with -lsdl3 in the Project.toml
-pub fn generate_library_args(libs: &Vec<PathBuf>) -> Vec<String> {
+pub fn generate_library_args(libs: &[PathBuf]) -> Vec<String> {
     let mut args = Vec::new();
     for l in libs {
         let arg = "-L".to_string() + l.parent().unwrap().to_str().unwrap();
@@ -309,32 +804,160 @@ pub fn generate_library_args(libs: &Vec<PathBuf>) -> Vec<String> {
     args
 }
 
+/// ! Appends the include flags to `compiler_args`: `-I` for `extra`, then the project's
+/// ! `include_dirs` and the discovered header directories, `-isystem` for dependency headers
+/// ! and the `--cflags` of `[System]` packages
 pub fn generate_include_args(
-    root: &PathBuf,
+    root: &Path,
+    platform: &BuildPlatform,
     dirs: &BuildDirs,
-    shared: &Vec<BuildShared>,
-    compiler_args: &Vec<String>,
-) -> Vec<String> {
-    let headers = find_headers(&dirs.sources);
-    let mut includes = generate_include_paths(&root, headers);
-    // Add the shared dependency includes to the list
-    for dep in shared {
-        includes.insert(dep.headers.clone());
+    shared: &[BuildShared],
+    extra: &[PathBuf],
+    compiler_args: &[String],
+) -> KnobResult<Vec<String>> {
+    let mut includes: Vec<PathBuf> = extra.to_vec();
+    includes.extend(dirs.includes.iter().cloned());
+    if dirs.discover_includes {
+        let headers = find_headers(&dirs.sources, &dirs.filter)?;
+        // HashSet order changes between runs, which would change the command line of every
+        // translation unit and make the build state consider all of them stale
+        let mut discovered: Vec<PathBuf> =
+            generate_include_paths(root, headers).into_iter().collect();
+        discovered.sort();
+        includes.extend(discovered);
     }
+    // The first occurrence wins, later duplicates wouldn't change the search order
+    let mut seen = HashSet::new();
+    includes.retain(|i| seen.insert(i.clone()));
+
+    let mut system: Vec<PathBuf> = shared.iter().map(|dep| dep.headers.clone()).collect();
+    system.sort();
+    system.dedup();
 
     let mut includes_args: Vec<String> = includes
         .iter()
         .map(|i| "-I".to_string() + i.to_str().unwrap())
         .collect();
-    let mut isys_args = includes.iter().fold(Vec::new(), |mut a, i| {
+    let mut isys_args = system.iter().fold(Vec::new(), |mut a, i| {
         a.push("-isystem".to_string());
         a.push(i.to_str().unwrap().to_string());
         a
     });
 
-    let mut compiler_args = compiler_args.clone();
+    // System headers don't warn either, packages often share directories and defines
+    let mut seen = HashSet::new();
+    let mut system_args = Vec::new();
+    for flag in platform.system.iter().flat_map(|p| &p.cflags) {
+        if !seen.insert(flag) {
+            continue;
+        }
+        match flag.strip_prefix("-I") {
+            Some(dir) => {
+                system_args.push("-isystem".to_string());
+                system_args.push(dir.to_string());
+            }
+            None => system_args.push(flag.clone()),
+        }
+    }
+
+    let mut compiler_args = compiler_args.to_vec();
     compiler_args.append(&mut includes_args);
     compiler_args.append(&mut isys_args);
+    compiler_args.append(&mut system_args);
+
+    Ok(compiler_args)
+}
 
-    compiler_args
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> SourceFilter {
+        let strings = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        source_filter(
+            Path::new("Project.toml"),
+            "",
+            &strings(include),
+            &strings(exclude),
+        )
+        .unwrap()
+    }
+
+    /// Start of what `gcc -c main.c -MD -MF main.d` wrote for a header in `my dir/a file.h` and
+    /// one called `cost$.h`
+    const GCC_DEPFILE: &str = "/tmp/dep/build/main.c.o: main.c /usr/include/stdc-predef.h \\
+ my\\ dir/a\\ file.h util.h cost$$.h /usr/include/stdio.h \\
+ /usr/include/x86_64-linux-gnu/bits/libc-header-start.h \\
+ /usr/include/features.h
+";
+
+    #[test]
+    fn parse_depfile_gcc_output() {
+        let expected: Vec<PathBuf> = [
+            "main.c",
+            "/usr/include/stdc-predef.h",
+            "my dir/a file.h",
+            "util.h",
+            "cost$.h",
+            "/usr/include/stdio.h",
+            "/usr/include/x86_64-linux-gnu/bits/libc-header-start.h",
+            "/usr/include/features.h",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(parse_depfile(GCC_DEPFILE), expected);
+        assert_eq!(parse_depfile(&GCC_DEPFILE.replace('\n', "\r\n")), expected);
+    }
+
+    #[test]
+    fn parse_depfile_skips_phony_targets() {
+        // `-MP` adds an empty rule for every header
+        let contents = "main.o: main.c util.h\n\nutil.h:\n";
+        assert_eq!(
+            parse_depfile(contents),
+            vec![PathBuf::from("main.c"), PathBuf::from("util.h")]
+        );
+        assert!(parse_depfile("").is_empty());
+    }
+
+    #[test]
+    fn glob_set_anchors_and_names() {
+        let file = Path::new("Project.toml");
+        let patterns = ["/api/*.cpp".to_string(), "tests".to_string()];
+        let set = glob_set(file, "exclude", &patterns).unwrap();
+        assert!(set.is_match("api/window.cpp"));
+        assert!(!set.is_match("other/api/window.cpp"));
+        assert!(!set.is_match("api/backends/metal.cpp"));
+        assert!(set.is_match("tests"));
+        assert!(set.is_match("api/tests"));
+
+        let invalid = glob_set(file, "exclude", &["a[".to_string()]);
+        assert!(matches!(invalid, Err(KnobError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn exclude_matches_directories() {
+        let filter = filter(&[], &["backends", "other/", "*_test.cpp"]);
+        assert!(!filter.matches(Path::new("api/backends/metal/m.cpp")));
+        assert!(!filter.matches(Path::new("other/o.cpp")));
+        assert!(!filter.matches(Path::new("api/window_test.cpp")));
+        assert!(filter.matches(Path::new("api/window.cpp")));
+        assert!(filter.matches(Path::new("api/others/o.cpp")));
+    }
+
+    #[test]
+    fn include_matches_directories() {
+        for include in ["api", "api/", "./api"] {
+            let filter = filter(&[include], &[]);
+            assert!(filter.matches(Path::new("api/window.cpp")), "{}", include);
+            assert!(filter.matches(Path::new("api/backends/metal.cpp")));
+            assert!(!filter.matches(Path::new("main.cpp")));
+        }
+
+        let filter = filter(&["/api"], &["metal.cpp"]);
+        assert!(filter.matches(Path::new("api/window.cpp")));
+        assert!(!filter.matches(Path::new("engine/api/window.cpp")));
+        assert!(!filter.matches(Path::new("api/backends/metal.cpp")));
+    }
 }
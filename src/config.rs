@@ -0,0 +1,282 @@
+use crate::errors::*;
+use crate::types::*;
+use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// ! Schema of Project.toml
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProjectConfig {
+    pub sources: String,
+    pub dependencies: String,
+    pub output: String,
+    /// ! Globs of the sources to compile, relative to `sources`. A pattern without `/` matches
+    /// ! a file or directory name at any depth
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// ! Passed as `-I` in this order, relative to Project.toml
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+    /// ! Also adds every directory holding a header under `sources`, and all of its parents
+    #[serde(default)]
+    pub discover_includes: bool,
+    /// ! `warn` or `error` when discovered headers share a file name
+    #[serde(default)]
+    pub ambiguous_headers: AmbiguousHeaders,
+    /// ! Strips the project directory from objects and pins timestamps to `SOURCE_DATE_EPOCH`
+    #[serde(default)]
+    pub reproducible: bool,
+    #[serde(rename = "Platform")]
+    pub platforms: BTreeMap<String, PlatformConfig>,
+    #[serde(rename = "Target", default)]
+    pub targets: BTreeMap<String, TargetConfig>,
+    #[serde(rename = "Profile", default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    #[serde(rename = "Dependency", default)]
+    pub fetch: BTreeMap<String, FetchConfig>,
+    #[serde(rename = "System", default)]
+    pub system: BTreeMap<String, SystemConfig>,
+}
+
+/// ! `[Platform.<os>]` in Project.toml
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlatformConfig {
+    pub compiler: String,
+    pub linker: String,
+    #[serde(default = "default_archiver")]
+    pub archiver: String,
+    #[serde(default)]
+    pub compiler_args: Vec<String>,
+    #[serde(default)]
+    pub linker_args: Vec<String>,
+    pub c: Option<ToolchainConfig>,
+    pub cpp: Option<ToolchainConfig>,
+    pub objc: Option<ToolchainConfig>,
+    pub objcpp: Option<ToolchainConfig>,
+    pub asm: Option<ToolchainConfig>,
+    /// ! Binary resolving `[System]` packages, a cross build may use `aarch64-linux-gnu-pkg-config`
+    #[serde(default = "default_pkg_config")]
+    pub pkg_config: String,
+    /// ! Searched before the `PKG_CONFIG_PATH` knob was started with, relative to Project.toml
+    #[serde(default)]
+    pub pkg_config_path: Vec<String>,
+    /// ! Exported as `PKG_CONFIG_SYSROOT_DIR`, relative to Project.toml
+    pub pkg_config_sysroot: Option<String>,
+}
+
+impl PlatformConfig {
+    pub fn toolchains(&self) -> Vec<(Language, &ToolchainConfig)> {
+        [
+            (Language::C, &self.c),
+            (Language::Cpp, &self.cpp),
+            (Language::ObjC, &self.objc),
+            (Language::ObjCpp, &self.objcpp),
+            (Language::Asm, &self.asm),
+        ]
+        .into_iter()
+        .filter_map(|(language, toolchain)| toolchain.as_ref().map(|t| (language, t)))
+        .collect()
+    }
+}
+
+/// ! `[Platform.<os>.<language>]` in Project.toml, languages are `c`, `cpp`, `objc`, `objcpp`
+/// ! and `asm`
+#[derive(Deserialize, Debug, Clone)]
+pub struct ToolchainConfig {
+    /// ! Defaults to the platform's compiler
+    pub compiler: Option<String>,
+    #[serde(default)]
+    pub compiler_args: Vec<String>,
+}
+
+/// ! `[Target.<name>]` in Project.toml
+#[derive(Deserialize, Debug, Clone)]
+pub struct TargetConfig {
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub kind: TargetKind,
+    /// ! Lets a static and a shared target produce `libengine.a` and `libengine.so`
+    pub output_name: Option<String>,
+    #[serde(rename = "deps", default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub compiler_args: Vec<String>,
+    #[serde(default)]
+    pub linker_args: Vec<String>,
+    /// ! Narrow down the project objects linked into this target, same syntax as the project's
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// ! Searched before the project's, only when compiling the entrypoint
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+}
+
+/// ! `[Dependency.<name>]` in Project.toml, where `knob fetch` gets `<dependencies>/<name>` from.
+/// ! Exactly one of `git`, `archive` and `path` is set
+#[derive(Deserialize, Debug, Clone)]
+pub struct FetchConfig {
+    /// ! URL or path of a repository, the default branch is used without `tag` or `rev`
+    pub git: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    /// ! .zip or anything tar can unpack
+    pub archive: Option<String>,
+    /// ! Directory copied as is
+    pub path: Option<String>,
+    /// ! Dependency.toml to use when the fetched tree doesn't have one
+    pub manifest: Option<String>,
+}
+
+/// ! One table per dependency in knob.lock
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockEntry {
+    /// ! `FetchSource::describe`, a pin is dropped when its dependency's source changes
+    pub source: String,
+    pub commit: Option<String>,
+    pub sha256: Option<String>,
+}
+
+/// ! `[Profile.<name>]` in Project.toml, extra arguments used by every target of a build profile
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProfileConfig {
+    #[serde(default)]
+    pub compiler_args: Vec<String>,
+    #[serde(default)]
+    pub linker_args: Vec<String>,
+}
+
+impl ProfileConfig {
+    /// ! Profiles available without being declared, a `[Profile]` with the same name replaces them
+    pub fn builtin() -> BTreeMap<String, ProfileConfig> {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect();
+        BTreeMap::from([
+            (
+                "debug".to_string(),
+                ProfileConfig {
+                    compiler_args: args(&["-g", "-O0"]),
+                    linker_args: Vec::new(),
+                },
+            ),
+            (
+                "release".to_string(),
+                ProfileConfig {
+                    compiler_args: args(&["-O2", "-DNDEBUG"]),
+                    linker_args: Vec::new(),
+                },
+            ),
+        ])
+    }
+}
+
+/// ! Schema of the Dependency.toml found in every folder of the dependencies directory
+#[derive(Deserialize, Debug, Clone)]
+pub struct DependencyConfig {
+    /// ! Required by the `script` driver, the others default to the `include` they installed
+    pub headers: Option<String>,
+    #[serde(default)]
+    pub driver: Driver,
+    /// ! Required by the `script` driver, the others generate it
+    #[serde(default)]
+    pub build: String,
+    #[serde(default)]
+    pub clean: String,
+    /// ! Names of the dependencies that have to be built before this one
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// ! Kept in declaration order, link order matters for static libraries
+    #[serde(rename = "Libraries", default)]
+    pub libraries: IndexMap<String, String>,
+    #[serde(rename = "Objects", default)]
+    pub objects: IndexMap<String, String>,
+    /// ! `-D<name>=<value>` for cmake and meson, `<name>=<value>` for make and configure
+    #[serde(default)]
+    pub defines: IndexMap<String, String>,
+    /// ! Appended to the configure step of cmake, meson and autotools
+    #[serde(default)]
+    pub configure_flags: Vec<String>,
+    /// ! Built instead of the default target, nothing is installed then
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+/// ! `[System.<name>]` in Project.toml, a library installed on the system and found with pkg-config
+#[derive(Deserialize, Debug, Clone)]
+pub struct SystemConfig {
+    /// ! pkg-config module, the table name when omitted
+    pub package: Option<String>,
+    /// ! Constraint on the module version, such as `>= 1.6`
+    pub version: Option<String>,
+    /// ! Links with `--libs --static`, which includes private dependencies
+    #[serde(rename = "static", default)]
+    pub static_libs: bool,
+    /// ! Keys of the `[Platform]` tables the package is used on, all of them when empty
+    #[serde(default)]
+    pub platforms: Vec<String>,
+}
+
+fn default_pkg_config() -> String {
+    "pkg-config".to_string()
+}
+
+fn default_archiver() -> String {
+    "ar".to_string()
+}
+
+/// ! Reads a TOML file into one of the schemas above, printing a warning for every key that
+/// ! isn't part of the schema so that typos don't go unnoticed
+pub fn read_config<T: DeserializeOwned>(file: &Path) -> KnobResult<T> {
+    let toml = fs::read_to_string(file).map_err(|e| KnobError::io(file, e))?;
+
+    let mut unknown = Vec::new();
+    let mut ignored = |key: serde_ignored::Path| unknown.push(key.to_string());
+    let deserializer = toml::Deserializer::new(&toml);
+    let deserializer = serde_ignored::Deserializer::new(deserializer, &mut ignored);
+    let config = serde_path_to_error::deserialize(deserializer);
+
+    for key in unknown {
+        eprintln!("warning: {}: unknown key `{}`", file.display(), key);
+    }
+    config.map_err(|e| schema_error(file, e))
+}
+
+/// ! Turns a deserialization error into a `KnobError` carrying the TOML path of the bad key
+fn schema_error(file: &Path, e: serde_path_to_error::Error<toml::de::Error>) -> KnobError {
+    let key = e.path().to_string();
+    let e = e.into_inner();
+
+    if let Some(field) = e
+        .message()
+        .strip_prefix("missing field `")
+        .and_then(|m| m.strip_suffix('`'))
+    {
+        let key = if key == "." {
+            field.to_string()
+        } else {
+            format!("{}.{}", key, field)
+        };
+        return KnobError::MissingKey {
+            file: file.to_path_buf(),
+            key,
+        };
+    }
+
+    if key == "." {
+        KnobError::Parse {
+            file: file.to_path_buf(),
+            message: e.to_string(),
+        }
+    } else {
+        KnobError::InvalidValue {
+            file: file.to_path_buf(),
+            key,
+            message: e.message().to_string(),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::*;
+use crate::errors::*;
+use crate::types::*;
+use std::path::Path;
+
+/// ! Build script of a dependency, its `build` for the `script` driver. The other drivers
+/// ! generate one that configures the dependency's build system with the toolchain exported by
+/// ! `dependency_env`, builds out of tree in `$KNOB_OUT_DIR/build` and installs into
+/// ! `$KNOB_OUT_DIR`. The build directory starts fresh every time. Every step runs at most
+/// ! `$KNOB_JOBS` processes, the dependency's share of the job limit
+pub fn driver_script(file: &Path, dep: &DependencyConfig) -> KnobResult<String> {
+    let invalid = |key: &str, message: &str| KnobError::InvalidValue {
+        file: file.to_path_buf(),
+        key: key.to_string(),
+        message: message.to_string(),
+    };
+    if dep.driver == Driver::Script {
+        if dep.build.is_empty() {
+            return Err(KnobError::MissingKey {
+                file: file.to_path_buf(),
+                key: "build".to_string(),
+            });
+        }
+        let options = [
+            ("defines", dep.defines.is_empty()),
+            ("configure_flags", dep.configure_flags.is_empty()),
+            ("targets", dep.targets.is_empty()),
+        ];
+        if let Some((key, _)) = options.iter().find(|(_, empty)| !empty) {
+            return Err(invalid(
+                key,
+                "only used by the cmake, make, meson and autotools drivers",
+            ));
+        }
+        return Ok(dep.build.clone());
+    }
+    if !dep.build.is_empty() {
+        return Err(invalid(
+            "build",
+            "generated by the driver, use `driver = \"script\"`",
+        ));
+    }
+    if dep.driver == Driver::Make && !dep.configure_flags.is_empty() {
+        return Err(invalid("configure_flags", "make has no configure step"));
+    }
+
+    let defines: Vec<String> = dep
+        .defines
+        .iter()
+        .map(|(name, value)| quote(&format!("{}={}", name, value)))
+        .collect();
+    let flags: Vec<String> = dep.configure_flags.iter().map(|f| quote(f)).collect();
+    let install = dep.targets.is_empty();
+
+    let mut script = vec![
+        "set -e".to_string(),
+        "B=\"$KNOB_OUT_DIR/build\"".to_string(),
+        "rm -rf \"$B\"".to_string(),
+        "mkdir -p \"$B\"".to_string(),
+    ];
+    match dep.driver {
+        Driver::Script => unreachable!(),
+        Driver::Cmake => {
+            script.push(build_type("Release", "Debug"));
+            let mut configure = vec![
+                "cmake -S . -B \"$B\"".to_string(),
+                "-DCMAKE_BUILD_TYPE=\"$TYPE\"".to_string(),
+                "-DCMAKE_INSTALL_PREFIX=\"$KNOB_OUT_DIR\"".to_string(),
+                "-DCMAKE_INSTALL_LIBDIR=lib".to_string(),
+                "-DCMAKE_C_COMPILER=\"$CC\"".to_string(),
+                "-DCMAKE_CXX_COMPILER=\"$CXX\"".to_string(),
+                "-DCMAKE_AR=\"$(command -v \"$AR\")\"".to_string(),
+            ];
+            configure.extend(defines.iter().map(|d| format!("-D{}", d)));
+            configure.extend(flags);
+            script.push(configure.join(" "));
+            let mut build = vec!["cmake --build \"$B\" --parallel \"$KNOB_JOBS\"".to_string()];
+            build.extend(dep.targets.iter().map(|t| format!("--target {}", quote(t))));
+            script.push(build.join(" "));
+            if install {
+                script.push("cmake --install \"$B\"".to_string());
+            }
+        }
+        Driver::Make => {
+            // Plain Makefiles build next to their sources, so they build in a copy
+            script.push("cp -R . \"$B\"".to_string());
+            let mut build = vec![
+                "make -C \"$B\" -j\"$KNOB_JOBS\"".to_string(),
+                "CC=\"$CC\" CXX=\"$CXX\" AR=\"$AR\"".to_string(),
+            ];
+            build.extend(defines);
+            build.extend(dep.targets.iter().map(|t| quote(t)));
+            script.push(build.join(" "));
+        }
+        Driver::Meson => {
+            script.push(build_type("release", "debug"));
+            let mut configure = vec![
+                "meson setup \"$B\" .".to_string(),
+                "--prefix \"$KNOB_OUT_DIR\" --libdir lib --buildtype \"$TYPE\"".to_string(),
+            ];
+            configure.extend(defines.iter().map(|d| format!("-D{}", d)));
+            configure.extend(flags);
+            script.push(configure.join(" "));
+            let mut build = vec!["meson compile -C \"$B\" -j \"$KNOB_JOBS\"".to_string()];
+            build.extend(dep.targets.iter().map(|t| quote(t)));
+            script.push(build.join(" "));
+            if install {
+                // Without `--no-rebuild` ninja would run again with as many jobs as CPUs
+                script.push("meson install -C \"$B\" --no-rebuild".to_string());
+            }
+        }
+        Driver::Autotools => {
+            // A checkout without a generated `configure` needs autoreconf first
+            script.push("[ -x ./configure ] || autoreconf -fi".to_string());
+            script.push("SRC=\"$PWD\"".to_string());
+            script.push("cd \"$B\"".to_string());
+            let mut configure = vec![
+                "\"$SRC/configure\"".to_string(),
+                "--prefix=\"$KNOB_OUT_DIR\" --libdir=\"$KNOB_OUT_DIR/lib\"".to_string(),
+            ];
+            configure.extend(defines);
+            configure.extend(flags);
+            script.push(configure.join(" "));
+            let mut build = vec!["make -j\"$KNOB_JOBS\"".to_string()];
+            build.extend(dep.targets.iter().map(|t| quote(t)));
+            script.push(build.join(" "));
+            if install {
+                script.push("make install".to_string());
+            }
+        }
+    }
+    Ok(script.join("\n"))
+}
+
+/// ! Sets `$TYPE` to `release` for the release profile and to `debug` for the others
+fn build_type(release: &str, debug: &str) -> String {
+    format!(
+        "if [ \"$KNOB_PROFILE\" = release ]; then TYPE={}; else TYPE={}; fi",
+        release, debug
+    )
+}
+
+/// ! Single quotes `arg` for bash
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
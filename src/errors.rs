@@ -0,0 +1,85 @@
+use std::fmt;
+use std::path::PathBuf;
+
+pub type KnobResult<T> = Result<T, KnobError>;
+
+/// ! Everything that can go wrong while reading the configuration or building
+#[derive(Debug)]
+pub enum KnobError {
+    /// ! A required key is absent, `key` is the full TOML path such as `Platform.linux.compiler`
+    MissingKey { file: PathBuf, key: String },
+    /// ! A key holds a value that doesn't fit the schema, such as a number instead of a string
+    InvalidValue {
+        file: PathBuf,
+        key: String,
+        message: String,
+    },
+    /// ! A file or directory referenced by the configuration doesn't exist
+    MissingPath { path: PathBuf },
+    /// ! A TOML or JSON file couldn't be parsed
+    Parse { file: PathBuf, message: String },
+    /// ! The configuration is well formed but can't be used as is
+    Config { file: PathBuf, message: String },
+    /// ! A compiler, linker or build script failed
+    Subprocess { command: String, message: String },
+    /// ! Reading or writing a file failed
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// ! Two sources would be compiled to the same object
+    Collision {
+        object: PathBuf,
+        sources: (PathBuf, PathBuf),
+    },
+    /// ! knob was invoked incorrectly
+    Usage(String),
+}
+
+impl fmt::Display for KnobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KnobError::MissingKey { file, key } => {
+                write!(f, "{}: missing key `{}`", file.display(), key)
+            }
+            KnobError::InvalidValue { file, key, message } => {
+                write!(f, "{}: `{}`: {}", file.display(), key, message)
+            }
+            KnobError::MissingPath { path } => write!(f, "{} doesn't exist", path.display()),
+            KnobError::Parse { file, message } => write!(f, "{}: {}", file.display(), message),
+            KnobError::Config { file, message } => write!(f, "{}: {}", file.display(), message),
+            KnobError::Subprocess { command, message } => {
+                write!(f, "`{}` failed: {}", command, message)
+            }
+            KnobError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            KnobError::Collision { object, sources } => write!(
+                f,
+                "{} and {} would both be compiled to {}",
+                sources.0.display(),
+                sources.1.display(),
+                object.display()
+            ),
+            KnobError::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for KnobError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KnobError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl KnobError {
+    pub fn io(path: impl Into<PathBuf>, error: std::io::Error) -> Self {
+        let path = path.into();
+        if error.kind() == std::io::ErrorKind::NotFound {
+            KnobError::MissingPath { path }
+        } else {
+            KnobError::Io { path, error }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use crate::buildstate::*;
+use crate::config::*;
+use crate::errors::*;
+use crate::procutils::*;
+use crate::types::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// ! Written in every fetched dependency, holds the description of where it came from
+const MARKER: &str = ".knob-fetch";
+
+/// ! Reads the `[Dependency]` tables of Project.toml, relative paths are resolved against the
+/// ! directory of Project.toml. Returns the dependencies directory along with them
+pub fn parse_fetch(file: &Path) -> KnobResult<(PathBuf, Vec<FetchDependency>)> {
+    let toml: ProjectConfig = read_config(file)?;
+    let project = file.parent().unwrap();
+
+    let mut fetched = Vec::new();
+    for (name, dep) in toml.fetch {
+        let source = match (&dep.git, &dep.archive, &dep.path) {
+            (Some(url), None, None) => {
+                let reference = match (&dep.tag, &dep.rev) {
+                    (Some(_), Some(_)) => {
+                        return Err(KnobError::Config {
+                            file: file.to_path_buf(),
+                            message: format!("[Dependency.{}] has both a tag and a rev", name),
+                        })
+                    }
+                    (tag, rev) => tag.clone().or(rev.clone()),
+                };
+                // Local repositories may be given relative to the project
+                let local = resolve(project, url);
+                let url = if !url.contains("://") && local.exists() {
+                    local.to_str().unwrap().to_string()
+                } else {
+                    url.clone()
+                };
+                FetchSource::Git { url, reference }
+            }
+            (None, Some(archive), None) => FetchSource::Archive(resolve(project, archive)),
+            (None, None, Some(path)) => FetchSource::Path(resolve(project, path)),
+            _ => {
+                return Err(KnobError::Config {
+                    file: file.to_path_buf(),
+                    message: format!(
+                        "[Dependency.{}] needs exactly one of `git`, `archive` or `path`",
+                        name
+                    ),
+                })
+            }
+        };
+        if dep.git.is_none() && (dep.tag.is_some() || dep.rev.is_some()) {
+            return Err(KnobError::InvalidValue {
+                file: file.to_path_buf(),
+                key: format!("Dependency.{}", name),
+                message: "`tag` and `rev` only apply to `git`".to_string(),
+            });
+        }
+        fetched.push(FetchDependency {
+            name,
+            source,
+            manifest: dep.manifest.map(|m| resolve(project, &m)),
+        });
+    }
+
+    Ok((resolve(project, &toml.dependencies), fetched))
+}
+
+/// ! `path` relative to `project`, without `..` when it exists
+fn resolve(project: &Path, path: &str) -> PathBuf {
+    let path = project.join(path);
+    fs::canonicalize(&path).unwrap_or(path)
+}
+
+/// ! Materializes every `[Dependency]` of Project.toml into the dependencies directory at the
+/// ! revisions pinned by knob.lock, pinning the new ones. Dependencies that are already where the
+/// ! lock says are left alone
+pub fn fetch_dependencies(file: &Path) -> KnobResult<()> {
+    sync_dependencies(file, false, None)
+}
+
+/// ! Fetches `name`, or every dependency, again from its source and pins what it got
+pub fn update_dependencies(file: &Path, name: Option<&str>) -> KnobResult<()> {
+    sync_dependencies(file, true, name)
+}
+
+fn sync_dependencies(file: &Path, update: bool, only: Option<&str>) -> KnobResult<()> {
+    let (deps, fetched) = parse_fetch(file)?;
+    mkdir(&deps)?;
+    let lock = file.with_file_name("knob.lock");
+    let mut locked = read_lock(&lock)?;
+
+    // Directories populated by hand can still be pinned when they are git checkouts
+    let mut checkouts = Vec::new();
+    let entries = fs::read_dir(&deps).map_err(|e| KnobError::io(&deps, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| KnobError::io(&deps, e))?;
+        let name = entry.file_name().to_str().unwrap().to_string();
+        if !name.starts_with('.')
+            && entry.path().join(".git").exists()
+            && !fetched.iter().any(|dep| dep.name == name)
+        {
+            checkouts.push(name);
+        }
+    }
+    checkouts.sort();
+
+    if let Some(only) = only {
+        if !fetched.iter().any(|dep| dep.name == only) && !checkouts.iter().any(|c| c == only) {
+            return Err(KnobError::Usage(format!(
+                "no [Dependency.{}] or git checkout in {}",
+                only,
+                deps.display()
+            )));
+        }
+    }
+    let refresh = |name: &str| update && only.is_none_or(|only| only == name);
+    // `knob update <name>` leaves the other dependencies and their pins alone
+    let skip = |name: &str| update && only.is_some_and(|only| only != name);
+
+    let mut pinned = BTreeMap::new();
+    for dep in &fetched {
+        if skip(&dep.name) {
+            pinned.extend(locked.remove_entry(&dep.name));
+            continue;
+        }
+        let source = dep.source.describe();
+        let pin = locked
+            .remove(&dep.name)
+            .filter(|pin| !refresh(&dep.name) && pin.source == source);
+        let pin = sync(&deps, dep, pin.as_ref(), refresh(&dep.name), &lock)?;
+        pinned.insert(dep.name.clone(), pin);
+    }
+    for name in checkouts {
+        if skip(&name) {
+            pinned.extend(locked.remove_entry(&name));
+            continue;
+        }
+        let pin = LockEntry {
+            source: "checkout".to_string(),
+            commit: Some(head(&deps.join(&name))?),
+            sha256: None,
+        };
+        if let Some(previous) = locked.remove(&name).filter(|_| !refresh(&name)) {
+            verify(&lock, &name, &previous, &pin)?;
+        }
+        pinned.insert(name, pin);
+    }
+
+    write_lock(&lock, &pinned)
+}
+
+/// ! Brings one dependency to its pinned revision, or fetches it from scratch when `refresh`.
+/// ! Returns the pin of what ends up in the dependencies directory
+fn sync(
+    deps: &Path,
+    dep: &FetchDependency,
+    locked: Option<&LockEntry>,
+    refresh: bool,
+    lock: &Path,
+) -> KnobResult<LockEntry> {
+    // Archives and directories are checked before anything gets replaced
+    if let (Some(locked), FetchSource::Archive(_) | FetchSource::Path(_)) = (locked, &dep.source) {
+        verify(lock, &dep.name, locked, &pin(deps, dep)?)?;
+    }
+
+    if !refresh && is_fetched(deps, dep) {
+        let current = pin(deps, dep)?;
+        if locked.is_none_or(|locked| *locked == current) {
+            println!("{} is up to date", dep.name);
+            return Ok(current);
+        }
+    }
+
+    fetch(deps, dep, locked.and_then(|l| l.commit.as_deref()))?;
+    let current = pin(deps, dep)?;
+    if let Some(locked) = locked {
+        verify(lock, &dep.name, locked, &current)?;
+    }
+    Ok(current)
+}
+
+/// ! Commit of a git dependency, SHA-256 of an archive or of the files of a directory
+fn pin(deps: &Path, dep: &FetchDependency) -> KnobResult<LockEntry> {
+    let (commit, sha256) = match &dep.source {
+        FetchSource::Git { .. } => (Some(head(&deps.join(&dep.name))?), None),
+        FetchSource::Archive(archive) => match hash_file(archive) {
+            Some(hash) => (None, Some(hash)),
+            None => {
+                return Err(KnobError::MissingPath {
+                    path: archive.clone(),
+                })
+            }
+        },
+        FetchSource::Path(path) => (None, Some(hash_tree(path)?)),
+    };
+    Ok(LockEntry {
+        source: dep.source.describe(),
+        commit,
+        sha256,
+    })
+}
+
+fn verify(lock: &Path, name: &str, locked: &LockEntry, current: &LockEntry) -> KnobResult<()> {
+    if locked == current {
+        return Ok(());
+    }
+    let describe = |pin: &LockEntry| match (&pin.commit, &pin.sha256) {
+        (Some(commit), _) => format!("commit {}", commit),
+        (_, Some(sha256)) => format!("sha256 {}", sha256),
+        _ => "nothing".to_string(),
+    };
+    Err(KnobError::Config {
+        file: lock.to_path_buf(),
+        message: format!(
+            "`{}` is pinned to {} but found {}, run `knob update {}` to accept it",
+            name,
+            describe(locked),
+            describe(current),
+            name
+        ),
+    })
+}
+
+fn head(repository: &Path) -> KnobResult<String> {
+    let args = ["-C", repository.to_str().unwrap(), "rev-parse", "HEAD"];
+    Ok(execute("git", &args.map(String::from), false, false)?
+        .trim()
+        .to_string())
+}
+
+/// ! SHA-256 over the relative path and content of every file, `.git` is left out
+fn hash_tree(root: &Path) -> KnobResult<String> {
+    if !root.is_dir() {
+        return Err(KnobError::MissingPath {
+            path: root.to_path_buf(),
+        });
+    }
+    let mut hasher = Sha256::new();
+    let walker = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+    for entry in walker {
+        let entry = entry.map_err(|e| KnobError::io(root, e.into()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let content = fs::read(entry.path()).map_err(|e| KnobError::io(entry.path(), e))?;
+        let relative = entry.path().strip_prefix(root).unwrap();
+        hasher.update(relative.to_str().unwrap().as_bytes());
+        hasher.update([0]);
+        hasher.update(content);
+        hasher.update([0]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// ! A missing knob.lock pins nothing
+fn read_lock(lock: &Path) -> KnobResult<BTreeMap<String, LockEntry>> {
+    if !lock.exists() {
+        return Ok(BTreeMap::new());
+    }
+    read_config(lock)
+}
+
+fn write_lock(lock: &Path, pinned: &BTreeMap<String, LockEntry>) -> KnobResult<()> {
+    let toml = toml::to_string(pinned).unwrap();
+    let toml = format!(
+        "# Written by `knob fetch` and `knob update`, commit it with Project.toml\n\n{}",
+        toml
+    );
+    fs::write(lock, toml).map_err(|e| KnobError::io(lock, e))
+}
+
+/// ! Whether `<deps>/<name>` holds what `dep` describes
+pub fn is_fetched(deps: &Path, dep: &FetchDependency) -> bool {
+    fs::read_to_string(deps.join(&dep.name).join(MARKER))
+        .is_ok_and(|previous| previous.trim() == dep.source.describe())
+}
+
+/// ! Replaces `<deps>/<name>` with a fresh copy of its source, git dependencies check out
+/// ! `commit` instead of their tag or rev when it is given
+fn fetch(deps: &Path, dep: &FetchDependency, commit: Option<&str>) -> KnobResult<()> {
+    let dest = deps.join(&dep.name);
+    if dest.exists() {
+        // Never delete a directory someone populated by hand
+        if !dest.join(MARKER).exists() {
+            return Err(KnobError::Usage(format!(
+                "{} wasn't fetched by knob, remove it to fetch [Dependency.{}]",
+                dest.display(),
+                dep.name
+            )));
+        }
+        println!("Removing outdated {}", dep.name);
+        rm(&dest)?;
+    }
+
+    println!("Fetching {} from {}", dep.name, dep.source.describe());
+    // Hidden so a half fetched dependency is never picked up by `parse_dependencies`
+    let tmp = deps.join(format!(".{}.fetch", dep.name));
+    rm(&tmp)?;
+    let fetched = materialize(deps, dep, commit, &tmp);
+    if fetched.is_err() {
+        rm(&tmp)?;
+        return fetched;
+    }
+    fs::rename(&tmp, &dest).map_err(|e| KnobError::io(&dest, e))
+}
+
+fn materialize(
+    deps: &Path,
+    dep: &FetchDependency,
+    commit: Option<&str>,
+    tmp: &Path,
+) -> KnobResult<()> {
+    match &dep.source {
+        FetchSource::Git { url, reference } => {
+            let tmp = tmp.to_str().unwrap().to_string();
+            let clone = ["clone", "--quiet", url, &tmp].map(String::from);
+            execute("git", &clone, true, false)?;
+            if let Some(reference) = commit.or(reference.as_deref()) {
+                let checkout = ["-C", &tmp, "checkout", "--quiet", "--detach", reference];
+                execute("git", &checkout.map(String::from), true, false)?;
+            }
+        }
+        FetchSource::Archive(archive) => {
+            let unpack = deps.join(format!(".{}.unpack", dep.name));
+            rm(&unpack)?;
+            let extracted = extract(archive, &unpack).and_then(|_| {
+                // Most archives wrap everything in a single `name-version/` directory
+                let entries: Vec<PathBuf> = fs::read_dir(&unpack)
+                    .map_err(|e| KnobError::io(&unpack, e))?
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .collect();
+                let root = match entries.as_slice() {
+                    [single] if single.is_dir() => single.clone(),
+                    _ => unpack.clone(),
+                };
+                fs::rename(&root, tmp).map_err(|e| KnobError::io(&root, e))
+            });
+            rm(&unpack)?;
+            extracted?;
+        }
+        FetchSource::Path(path) => copy_dir(path, tmp)?,
+    }
+
+    if let Some(manifest) = &dep.manifest {
+        let toml = tmp.join("Dependency.toml");
+        if !toml.exists() {
+            fs::copy(manifest, &toml).map_err(|e| KnobError::io(manifest, e))?;
+        }
+    }
+    fs::write(tmp.join(MARKER), dep.source.describe()).map_err(|e| KnobError::io(tmp, e))
+}
+
+/// ! Unpacks a .zip with unzip and every other archive with tar, which detects the compression
+fn extract(archive: &Path, into: &Path) -> KnobResult<()> {
+    if !archive.is_file() {
+        return Err(KnobError::MissingPath {
+            path: archive.to_path_buf(),
+        });
+    }
+    fs::create_dir_all(into).map_err(|e| KnobError::io(into, e))?;
+    let archive = archive.to_str().unwrap().to_string();
+    let into = into.to_str().unwrap().to_string();
+    if archive.ends_with(".zip") {
+        execute(
+            "unzip",
+            &["-q".to_string(), archive, "-d".to_string(), into],
+            true,
+            false,
+        )?;
+    } else {
+        execute(
+            "tar",
+            &["-xf".to_string(), archive, "-C".to_string(), into],
+            true,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+/// ! Copies a directory tree without its `.git`, build scripts must not write into the original
+fn copy_dir(from: &Path, to: &Path) -> KnobResult<()> {
+    if !from.is_dir() {
+        return Err(KnobError::MissingPath {
+            path: from.to_path_buf(),
+        });
+    }
+    let walker = WalkDir::new(from)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+    for entry in walker {
+        let entry = entry.map_err(|e| KnobError::io(from, e.into()))?;
+        let dest = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest).map_err(|e| KnobError::io(&dest, e))?;
+        } else {
+            fs::copy(entry.path(), &dest).map_err(|e| KnobError::io(entry.path(), e))?;
+        }
+    }
+    Ok(())
+}
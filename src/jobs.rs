@@ -0,0 +1,222 @@
+use crate::errors::*;
+use crate::procutils::*;
+use crate::types::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// ! A single process to be run by the job scheduler
+#[derive(Debug, Clone, Default)]
+pub struct Job {
+    pub label: String,
+    pub binary: String,
+    pub args: Vec<String>,
+    /// ! Working directory of the process, knob's own when `None`
+    pub dir: Option<PathBuf>,
+    /// ! Added to the environment knob was started with
+    pub env: Vec<(String, String)>,
+    /// ! The command line and output are written there instead of the terminal
+    pub log: Option<PathBuf>,
+}
+
+impl Job {
+    pub fn command_line(&self) -> String {
+        let mut command = self.binary.clone();
+        for a in &self.args {
+            command.push(' ');
+            command.push_str(a);
+        }
+        command
+    }
+}
+
+impl From<&CompileCommand> for Job {
+    fn from(command: &CompileCommand) -> Self {
+        Job {
+            label: format!(
+                "Compiling {}...",
+                command.source().file_name().unwrap().to_str().unwrap()
+            ),
+            binary: command.arguments[0].clone(),
+            args: command.arguments[1..].to_vec(),
+            dir: Some(PathBuf::from(&command.directory)),
+            ..Job::default()
+        }
+    }
+}
+
+/// ! Number of jobs used when `--jobs` isn't given
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// ! Runs `jobs` with at most `limit` processes at a time
+/// ! The output of each process is buffered and printed in one block once it exits, so
+/// ! diagnostics of different translation units never interleave.
+/// ! After the first failure no new jobs are started, the running ones are waited on and
+/// ! the error of the failed job is returned. `done` is called from the worker thread with the
+/// ! index and the outcome of every job that ran
+pub fn run_jobs_with<F>(jobs: Vec<Job>, limit: usize, done: F) -> KnobResult<()>
+where
+    F: Fn(usize, &KnobResult<()>) + Sync,
+{
+    let workers = limit.max(1).min(jobs.len());
+    let queue = Mutex::new(jobs.into_iter().enumerate().collect::<VecDeque<_>>());
+    let failed = AtomicBool::new(false);
+    let error: Mutex<Option<KnobError>> = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let (index, job) = match queue.lock().unwrap().pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+
+                let result = run_job(&job);
+                done(index, &result);
+                if let Err(e) = result {
+                    // Only the first failure is reported, later ones are a consequence of it
+                    if !failed.swap(true, Ordering::SeqCst) {
+                        *error.lock().unwrap() = Some(e);
+                    }
+                }
+            });
+        }
+    });
+
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// ! Runs tasks `0..requires.len()` with at most `limit` at a time, starting each one as soon as
+/// ! the tasks in its `requires` succeeded. `run` gets the index of the task and how many tasks
+/// ! are running or ready to, itself included, at most `limit`. After the first failure no new
+/// ! tasks are started. Returns the outcome of every task that ran, in the order they finished
+pub fn run_graph<F>(requires: &[Vec<usize>], limit: usize, run: F) -> Vec<(usize, KnobResult<()>)>
+where
+    F: Fn(usize, usize) -> KnobResult<()> + Sync,
+{
+    struct Graph {
+        started: Vec<bool>,
+        succeeded: Vec<bool>,
+        running: usize,
+        failed: bool,
+        outcomes: Vec<(usize, KnobResult<()>)>,
+    }
+    let count = requires.len();
+    let limit = limit.max(1);
+    let graph = Mutex::new(Graph {
+        started: vec![false; count],
+        succeeded: vec![false; count],
+        running: 0,
+        failed: false,
+        outcomes: Vec::new(),
+    });
+    let changed = Condvar::new();
+    let ready = |graph: &Graph, task: usize| {
+        !graph.started[task] && requires[task].iter().all(|r| graph.succeeded[*r])
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..limit.min(count) {
+            scope.spawn(|| {
+                let mut state = graph.lock().unwrap();
+                loop {
+                    if state.failed {
+                        break;
+                    }
+                    let next = (0..count).find(|t| ready(&state, *t));
+                    let Some(task) = next else {
+                        // Nothing is left, or what is left waits on a running task
+                        if state.running == 0 {
+                            break;
+                        }
+                        state = changed.wait(state).unwrap();
+                        continue;
+                    };
+                    state.started[task] = true;
+                    state.running += 1;
+                    let waiting = (0..count).filter(|t| ready(&state, *t)).count();
+                    let concurrent = (state.running + waiting).min(limit);
+                    drop(state);
+
+                    let result = run(task, concurrent);
+
+                    state = graph.lock().unwrap();
+                    state.running -= 1;
+                    match &result {
+                        Ok(()) => state.succeeded[task] = true,
+                        Err(_) => state.failed = true,
+                    }
+                    state.outcomes.push((task, result));
+                    changed.notify_all();
+                }
+                // Wake up the workers still waiting so they see the failure or the end
+                changed.notify_all();
+            });
+        }
+    });
+
+    graph.into_inner().unwrap().outcomes
+}
+
+/// ! Runs a single job to completion and prints its grouped output, or writes it to its log
+pub fn run_job(job: &Job) -> KnobResult<()> {
+    let mut command = Command::new(&job.binary);
+    command.args(&job.args).envs(job.env.iter().cloned());
+    if let Some(dir) = &job.dir {
+        command.current_dir(dir);
+    }
+    let output = command.output();
+
+    if let Some(log) = &job.log {
+        let mut file = fs::File::create(log).map_err(|e| KnobError::io(log, e))?;
+        let _ = writeln!(file, "{}", job.command_line());
+        if let Ok(output) = &output {
+            let _ = file.write_all(&output.stdout);
+            let _ = file.write_all(&output.stderr);
+        }
+    }
+
+    // Holding the stdout lock keeps the whole block together
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{}", job.label);
+    if job.log.is_none() {
+        let _ = writeln!(stdout, "{}", job.command_line());
+    }
+
+    let failed = |message: String| KnobError::Subprocess {
+        command: job.command_line(),
+        message,
+    };
+    match output {
+        Ok(output) => {
+            if job.log.is_none() {
+                let _ = stdout.write_all(&output.stdout);
+                let _ = stdout.flush();
+                let _ = std::io::stderr().write_all(&output.stderr);
+            }
+
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(failed(exit_status(&output.status)))
+            }
+        }
+        Err(e) => Err(failed(e.to_string())),
+    }
+}
@@ -1,13 +1,25 @@
+pub mod buildstate;
 pub mod buildsteps;
 pub mod compileutils;
+pub mod config;
+pub mod drivers;
+pub mod errors;
+pub mod fetch;
+pub mod jobs;
 pub mod procutils;
+pub mod scaffold;
+pub mod system;
 pub mod types;
+use crate::buildstate::*;
 use crate::compileutils::*;
+use crate::errors::*;
+use crate::fetch::*;
+use crate::jobs::*;
 use crate::procutils::*;
-use crate::types::*;
+use crate::scaffold::*;
 use buildsteps::*;
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::Path;
 
 /// ! knob shared -> knob build <TARGET>
 #[derive(Parser)]
@@ -16,24 +28,82 @@ struct Args {
     #[arg(short, long, default_value = ".")]
     root: String,
 
+    /// Number of compiler processes to run at once, defaults to the number of CPUs
+    #[arg(short, long, global = true)]
+    jobs: Option<usize>,
+
+    /// Build profile from [Profile] in Project.toml, `debug` and `release` are built in.
+    /// Defaults to `debug`
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+
+    /// Build shared dependencies even when their fingerprint didn't change
+    #[arg(long, global = true)]
+    rebuild_deps: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
+#[derive(clap::Args)]
+struct TargetArgs {
+    /// Name of the [Target] to build, may be omitted when the project has only one
+    target: Option<String>,
+
+    /// Build every [Target] in Project.toml
+    #[arg(long, conflicts_with = "target")]
+    all: bool,
+}
+
+#[derive(clap::Args)]
+struct InitArgs {
+    /// Language of the hello world entrypoint and compiler defaults
+    #[arg(long, value_enum, default_value = "cpp")]
+    lang: ProjectLanguage,
+
+    /// Name of the [Target], defaults to the name of the project directory
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Overwrite an existing Project.toml
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(clap::Args)]
+struct UpdateArgs {
+    /// Name of the dependency to update, every dependency when omitted
+    name: Option<String>,
+}
+
 #[derive(Subcommand)]
+#[allow(clippy::upper_case_acronyms)]
 enum Commands {
-    INIT,
+    INIT(InitArgs),
     CLEAN,
     PROJECT,
-    BUILD,
-    RELEASE,
-    INC,
+    BUILD(TargetArgs),
+    RELEASE(TargetArgs),
+    INC(TargetArgs),
     CHECK,
-    SHARED,
+    SHARED(TargetArgs),
+    /// Fetch the [Dependency] entries of Project.toml into the dependencies directory
+    FETCH,
+    /// Fetch dependencies again from their source and pin them in knob.lock
+    UPDATE(UpdateArgs),
 }
 
 fn main() {
     let args = Args::parse();
-    let root = canonicalize(&args.root);
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> KnobResult<()> {
+    let root = canonicalize(&args.root)?;
+    let jobs = args.jobs.unwrap_or_else(default_jobs);
+    let profile = args.profile.as_deref().unwrap_or("debug");
     let mut toml = root.clone();
     toml.push("Project.toml");
     let mut compile_commands = root.clone();
@@ -45,138 +115,174 @@ fn main() {
     match args.command {
         Some(x) => match x {
             Commands::CHECK => {
-                let (dirs, platform, shared, targets) = parse_toml(&*toml);
+                let (dirs, _, shared, targets) = parse_toml(&toml, profile)?;
+                check_ambiguous_headers(&toml, &dirs)?;
                 println!("Directories\n{:#?}", dirs);
                 println!("Dependencies\n{:#?}", shared);
                 println!("Targets\n{:#?}", targets);
             }
-            Commands::INIT => {
-                todo!()
+            Commands::FETCH => {
+                fetch_dependencies(&toml)?;
+            }
+            Commands::UPDATE(update) => {
+                update_dependencies(&toml, update.name.as_deref())?;
+            }
+            Commands::INIT(init) => {
+                init_project(&root, init.name.as_deref(), init.lang, init.force)?;
             }
             Commands::CLEAN => {
-                let (dirs, _, shared, target) = parse_toml(&*toml);
-                for dep in shared {
+                let (dirs, platform, shared, targets) = parse_toml(&toml, profile)?;
+                for dep in &shared {
                     println!(
                         "Cleaning {}",
                         dep.root.file_name().unwrap().to_str().unwrap()
                     );
-                    let prev = cwd();
-                    cd(&dep.root.clone());
-                    execute(
-                        "bash",
-                        &vec!["-c".to_string(), dep.clean.clone()],
-                        false,
-                        false,
-                    )
-                    .unwrap();
-                    cd(&prev.clone());
+                    let env = dependency_env(&platform, &shared, dep, jobs);
+                    let clean = ["-c".to_string(), dep.clean.clone()];
+                    let prev = cwd()?;
+                    cd(&dep.root.clone())?;
+                    let cleaned = execute_env("bash", &clean, &env, false, false);
+                    cd(&prev.clone())?;
+                    cleaned?;
                 }
 
                 println!("Removing {:?}", dirs.output);
-                rm(&dirs.output);
+                rm(&dirs.output)?;
                 println!("Removing libdependencies.a");
                 let mut libdep = root.clone();
                 libdep.push("./libdependencies.a");
-                rm(&libdep);
+                rm(&libdep)?;
 
                 println!("Removing compile_commands.json");
-                rm(&compile_commands);
+                rm(&compile_commands)?;
 
-                println!("Removing {}", target.name);
-                let mut bin = root.clone();
-                bin.push(&target.name);
-                rm(&bin);
+                for target in targets {
+                    println!("Removing {}", target.artifact());
+                    let mut bin = root.clone();
+                    bin.push(target.artifact());
+                    rm(&bin)?;
+                }
             }
-            Commands::SHARED => {
-                let (dirs, platform, shared, target) = parse_toml(&*toml);
+            Commands::SHARED(selection) => {
+                let (dirs, platform, shared, targets) = parse_toml(&toml, profile)?;
+                let targets =
+                    select_targets(&toml, &targets, selection.target.as_deref(), selection.all)?;
                 println!("Building Shared Dependencies...");
-                build_shared(&shared, &target.dependencies);
+                let filters = target_dependencies(&targets);
+                build_shared(
+                    &root,
+                    &platform,
+                    &shared,
+                    &dirs,
+                    &filters,
+                    args.rebuild_deps,
+                    jobs,
+                )?;
             }
             Commands::PROJECT => {
-                let (dirs, platform, shared, target) = parse_toml(&*toml);
+                let (dirs, platform, shared, _) = parse_toml(&toml, profile)?;
+                check_ambiguous_headers(&toml, &dirs)?;
 
-                compile_project(&root, &platform, &shared, &dirs);
+                let mut state = BuildState::load(&dirs.output);
+                let commands =
+                    compile_project(&root, &platform, &shared, &dirs, &[], &mut state, jobs)?;
+                write_compile_commands(&compile_commands, &commands)?;
             }
-            Commands::INC => {
-                let (dirs, platform, shared, target) = parse_toml(&*toml);
-                let compile_commands =
-                    std::fs::read_to_string(&*compile_commands.into_boxed_path()).unwrap();
-                let compile_commands: CompileCommands =
-                    serde_json::from_str(&compile_commands).unwrap();
-
-                let headers = find_headers(&dirs.sources);
-                let sources = find_sources(&dirs.sources);
-                let mut includes = generate_include_paths(&root, headers);
-                // Add the shared dependency includes to the list
-                for dep in shared.clone() {
-                    includes.insert(dep.headers.clone());
-                }
+            Commands::INC(selection) => {
+                let (dirs, platform, shared, targets) = parse_toml(&toml, profile)?;
+                let selected =
+                    select_targets(&toml, &targets, selection.target.as_deref(), selection.all)?;
+                check_ambiguous_headers(&toml, &dirs)?;
+                let mut state = BuildState::load(&dirs.output);
 
-                let mut includes_args: Vec<String> = includes
-                    .iter()
-                    .map(|i| "-I".to_string() + i.to_str().unwrap())
-                    .collect();
-                let mut isys_args = includes.iter().fold(Vec::new(), |mut a, i| {
-                    a.push("-isystem".to_string());
-                    a.push(i.to_str().unwrap().to_string());
-                    a
-                });
-
-                let mut compiler_args = platform.compiler_args.clone();
-                compiler_args.append(&mut target.compiler_args.clone());
-                compiler_args.append(&mut includes_args.clone());
-                compiler_args.append(&mut isys_args.clone());
-                let mut objs = Vec::new();
-                for command in compile_commands.0.clone() {
-                    let bin = command.arguments[0].clone();
-                    let args = command.arguments[1..].to_vec();
-                    let src = PathBuf::from(&command.file);
-                    let obj = PathBuf::from(&command.output);
-                    objs.push(obj);
-
-                    let o = last_modified(&command.output);
-                    let c = last_modified(&command.file);
-
-                    if o.is_err() || c.is_err() || o.unwrap() < c.unwrap() {
-                        println!("Rebuilding {}", command.output);
-                        execute(&bin, &args, false, true).unwrap();
-                    }
+                // Same commands as `knob build`, so flag changes in Project.toml recompile
+                let project = compile_project(
+                    &root, &platform, &shared, &dirs, &selected, &mut state, jobs,
+                )?;
+                let mut commands = project.clone();
+                for target in selected {
+                    let entrypoint = build_target(
+                        &root, &platform, &shared, &dirs, &target, &project, &mut state,
+                    )?;
+                    commands.extend(entrypoint);
                 }
-
-                let bin = compile(
-                    &platform.compiler,
-                    &target.entrypoint,
-                    &dirs.output,
-                    &compiler_args,
-                );
-                objs.push(bin.clone());
-
-                link_binary(&root.clone(), &platform, &shared, &target, &objs);
+                write_compile_commands(&compile_commands, &commands)?;
             }
-            Commands::BUILD => {
-                let (dirs, platform, shared, target) = parse_toml(&*toml);
-
-                println!("Building Shared Depenencies...");
-                build_shared(&shared, &target.dependencies);
-
-                println!("Compiling Project...");
-                let mut objs = compile_project(&root, &platform, &shared, &dirs);
-
-                // Compile the entrypoint
-                let mut args =
-                    generate_include_args(&root, &dirs, &shared, &platform.compiler_args.clone());
-
-                println!("Compiling {}...", target.name);
-                let entrypoint =
-                    compile(&platform.compiler, &target.entrypoint, &dirs.output, &args);
-                objs.push(entrypoint);
-                println!("Linking Binary...");
-                link_binary(&root, &platform, &shared, &target, &objs);
+            Commands::BUILD(selection) => {
+                build(
+                    &root,
+                    &toml,
+                    &compile_commands,
+                    &selection,
+                    profile,
+                    jobs,
+                    args.rebuild_deps,
+                )?;
+            }
+            Commands::RELEASE(selection) => {
+                // Shorthand for `knob build --profile release`
+                if args.profile.as_deref().is_some_and(|p| p != "release") {
+                    return Err(KnobError::Usage(
+                        "`knob release` always uses the release profile, use `knob build --profile` instead".to_string(),
+                    ));
+                }
+                build(
+                    &root,
+                    &toml,
+                    &compile_commands,
+                    &selection,
+                    "release",
+                    jobs,
+                    args.rebuild_deps,
+                )?;
             }
-            Commands::RELEASE => {}
         },
         None => {
-            panic!("Specify an action")
+            return Err(KnobError::Usage(
+                "Specify an action, see `knob --help`".to_string(),
+            ))
         }
     }
+
+    Ok(())
+}
+
+fn build(
+    root: &Path,
+    toml: &Path,
+    compile_commands: &Path,
+    selection: &TargetArgs,
+    profile: &str,
+    jobs: usize,
+    rebuild_deps: bool,
+) -> KnobResult<()> {
+    let (dirs, platform, shared, targets) = parse_toml(toml, profile)?;
+    let targets = select_targets(toml, &targets, selection.target.as_deref(), selection.all)?;
+    check_ambiguous_headers(toml, &dirs)?;
+
+    println!("Building Shared Depenencies...");
+    let filters = target_dependencies(&targets);
+    build_shared(
+        root,
+        &platform,
+        &shared,
+        &dirs,
+        &filters,
+        rebuild_deps,
+        jobs,
+    )?;
+
+    println!("Compiling Project...");
+    let mut state = BuildState::load(&dirs.output);
+    let project = compile_project(root, &platform, &shared, &dirs, &targets, &mut state, jobs)?;
+    let mut commands = project.clone();
+
+    // Project objects are shared, only the entrypoints differ between targets
+    for target in targets {
+        let entrypoint = build_target(
+            root, &platform, &shared, &dirs, &target, &project, &mut state,
+        )?;
+        commands.extend(entrypoint);
+    }
+    write_compile_commands(compile_commands, &commands)
 }
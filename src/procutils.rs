@@ -1,99 +1,102 @@
-use crate::types::*;
-use std::env::consts;
+use crate::errors::*;
 use std::io;
 use std::io::Result;
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::process::Stdio;
-use std::time::*;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// ! Change the working directory of the build process
-pub fn cd(relative: &PathBuf) {
-    let base_path = std::env::current_dir().unwrap();
+pub fn cd(relative: &Path) -> KnobResult<()> {
+    let base_path = cwd()?;
     let base_path = base_path.join(relative);
 
     //println!("CD: {:?}", base_path);
-    let absolute = std::fs::canonicalize(&base_path)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
+    let absolute = std::fs::canonicalize(&base_path).map_err(|e| KnobError::io(&base_path, e))?;
 
-    std::env::set_current_dir(&absolute).unwrap();
+    std::env::set_current_dir(&absolute).map_err(|e| KnobError::io(&absolute, e))
 }
 
-///! Check if binary exists
+/// ! Check if binary exists
 pub fn bin_exists(bin: &str) -> bool {
-    match Command::new(bin).output() {
-        Ok(status) => return true,
-        Err(e) => return false,
-    }
+    Command::new(bin).output().is_ok()
 }
 
 /// ! Execute a Binary with Command Line Arguments
-pub fn execute(
+pub fn execute(binary: &str, args: &[String], echo: bool, stream: bool) -> KnobResult<String> {
+    execute_env(binary, args, &[], echo, stream)
+}
+
+/// ! `execute` with extra environment variables for the child process
+pub fn execute_env(
     binary: &str,
-    args: &Vec<String>,
+    args: &[String],
+    env: &[(String, String)],
     echo: bool,
     stream: bool,
-) -> std::result::Result<String, String> {
+) -> KnobResult<String> {
     let mut _command = String::new();
     _command.push_str(binary);
     for a in args {
-        _command.push_str(" ");
+        _command.push(' ');
         _command.push_str(a);
     }
     if echo {
         println!("{}", _command);
     }
 
+    let failed = |message: String| KnobError::Subprocess {
+        command: _command.clone(),
+        message,
+    };
+
     if !stream {
-        match Command::new(binary).args(args).output() {
+        match Command::new(binary).args(args).envs(env.to_vec()).output() {
             Ok(output) => {
                 let ok = output.status.success();
-                let code = output.status.code().unwrap();
 
-                let stdout = String::from_utf8(output.stdout).unwrap();
-                let stderr = String::from_utf8(output.stderr).unwrap();
+                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
 
                 if ok {
                     Ok(stdout)
                 } else {
-                    Err(stderr)
+                    Err(failed(stderr))
                 }
             }
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(failed(e.to_string())),
         }
     } else {
         let mut command = Command::new(binary);
-        let process = command.args(args);
+        let process = command.args(args).envs(env.to_vec());
         process.stdout(Stdio::inherit());
         process.stderr(Stdio::inherit());
         let mut handle = {
             match process.spawn() {
                 Ok(handle) => handle,
                 Err(e) => {
-                    return Err(e.to_string());
+                    return Err(failed(e.to_string()));
                 }
             }
         };
 
-        loop {
-            let status = handle.wait().unwrap();
-            if status.success() {
-                return Ok("".to_string());
-            } else {
-                return Err(format!(
-                    "{} exited with code {}",
-                    binary,
-                    status.code().unwrap()
-                ));
-            }
+        let status = handle.wait().map_err(|e| failed(e.to_string()))?;
+        if status.success() {
+            Ok("".to_string())
+        } else {
+            Err(failed(exit_status(&status)))
         }
     }
 }
 
+/// ! Describes how a failed process exited
+pub fn exit_status(status: &std::process::ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exited with code {}", code),
+        None => "terminated by a signal".to_string(),
+    }
+}
+
 pub fn now() -> i64 {
     let unix_timestamp: i64 = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -121,28 +124,30 @@ pub fn since(timestamp1: u64, timestamp2: u64) -> u64 {
     difference.as_secs()
 }
 
-pub fn cwd() -> PathBuf {
-    let base_path = std::env::current_dir().unwrap();
-    std::fs::canonicalize(&base_path).unwrap()
+pub fn cwd() -> KnobResult<PathBuf> {
+    let base_path = std::env::current_dir().map_err(|e| KnobError::io(".", e))?;
+    std::fs::canonicalize(&base_path).map_err(|e| KnobError::io(&base_path, e))
 }
 
-pub fn rm(path: &PathBuf) {
+pub fn rm(path: &Path) -> KnobResult<()> {
     if path.exists() {
         if path.is_file() {
-            std::fs::remove_file(path).unwrap();
+            std::fs::remove_file(path).map_err(|e| KnobError::io(path, e))?;
         } else if path.is_dir() {
-            std::fs::remove_dir_all(path).unwrap();
+            std::fs::remove_dir_all(path).map_err(|e| KnobError::io(path, e))?;
         }
     }
+    Ok(())
 }
-pub fn mkdir(path: &PathBuf) {
+pub fn mkdir(path: &Path) -> KnobResult<()> {
     if !path.exists() {
-        std::fs::create_dir(path).unwrap();
+        std::fs::create_dir(path).map_err(|e| KnobError::io(path, e))?;
     }
+    Ok(())
 }
 
-pub fn canonicalize(rel_path: &str) -> PathBuf {
-    std::fs::canonicalize(rel_path).unwrap()
+pub fn canonicalize(rel_path: &str) -> KnobResult<PathBuf> {
+    std::fs::canonicalize(rel_path).map_err(|e| KnobError::io(rel_path, e))
 }
 
 pub fn last_modified(path: &String) -> Result<u64> {
@@ -150,6 +155,6 @@ pub fn last_modified(path: &String) -> Result<u64> {
     let modified_time = metadata.modified()?;
     let duration = modified_time
         .duration_since(UNIX_EPOCH)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Modified time is before UNIX epoch"))?;
+        .map_err(|_| io::Error::other("Modified time is before UNIX epoch"))?;
     Ok(duration.as_secs())
 }
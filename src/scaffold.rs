@@ -0,0 +1,148 @@
+use crate::errors::*;
+use crate::procutils::*;
+use std::fs;
+use std::path::Path;
+
+/// ! Language of the project created by `knob init`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectLanguage {
+    C,
+    Cpp,
+}
+
+impl ProjectLanguage {
+    fn extension(&self) -> &'static str {
+        match self {
+            ProjectLanguage::C => "c",
+            ProjectLanguage::Cpp => "cpp",
+        }
+    }
+    /// ! (linux, osx) compiler drivers, which are also used for linking
+    fn compilers(&self) -> (&'static str, &'static str) {
+        match self {
+            ProjectLanguage::C => ("gcc", "clang"),
+            ProjectLanguage::Cpp => ("g++", "clang++"),
+        }
+    }
+    fn hello_world(&self) -> &'static str {
+        match self {
+            ProjectLanguage::C => "#include <stdio.h>\n\nint main(void) {\n    printf(\"Hello, World!\\n\");\n    return 0;\n}\n",
+            ProjectLanguage::Cpp => "#include <iostream>\n\nint main() {\n    std::cout << \"Hello, World!\" << std::endl;\n    return 0;\n}\n",
+        }
+    }
+}
+
+/// ! Creates Project.toml, `./src/`, `./deps/`, a hello world entrypoint and a .gitignore in `root`
+/// ! An existing Project.toml is only replaced with `force`, existing sources are never touched
+pub fn init_project(
+    root: &Path,
+    name: Option<&str>,
+    lang: ProjectLanguage,
+    force: bool,
+) -> KnobResult<()> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => root.file_name().unwrap().to_str().unwrap().to_string(),
+    };
+    // The name is used both as a TOML key and as the binary's file name
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(KnobError::Usage(format!(
+            "`{}` isn't a valid target name, use --name with letters, digits, `_` and `-`",
+            name
+        )));
+    }
+
+    let toml = root.join("Project.toml");
+    if toml.exists() && !force {
+        return Err(KnobError::Usage(format!(
+            "{} already exists, use --force to overwrite it",
+            toml.display()
+        )));
+    }
+
+    let main = format!("{}.{}", name, lang.extension());
+    let entrypoint = format!("./bins/{}", main);
+    let (linux, osx) = lang.compilers();
+    let project = format!(
+        r#"sources = "./src"
+dependencies = "./deps"
+output = "./build"
+include_dirs = ["./src"]
+
+[Platform.linux]
+compiler = "{linux}"
+linker = "{linux}"
+archiver = "ar"
+compiler_args = []
+linker_args = []
+
+[Platform.linux.c]
+compiler = "gcc"
+compiler_args = ["-std=c17"]
+
+[Platform.linux.cpp]
+compiler = "g++"
+compiler_args = ["-std=c++20"]
+
+[Platform.osx]
+compiler = "{osx}"
+linker = "{osx}"
+archiver = "ar"
+compiler_args = []
+linker_args = []
+
+[Platform.osx.c]
+compiler = "clang"
+compiler_args = ["-std=c17"]
+
+[Platform.osx.cpp]
+compiler = "clang++"
+compiler_args = ["-std=c++20"]
+
+[Target.{name}]
+entrypoint = "{entrypoint}"
+deps = []
+compiler_args = []
+linker_args = []
+
+[Profile.debug]
+compiler_args = ["-g", "-O0"]
+
+[Profile.release]
+compiler_args = ["-O2", "-DNDEBUG"]
+"#,
+        linux = linux,
+        osx = osx,
+        name = name,
+        entrypoint = entrypoint,
+    );
+    println!("Writing {}", toml.display());
+    fs::write(&toml, project).map_err(|e| KnobError::io(&toml, e))?;
+
+    mkdir(&root.join("src"))?;
+    mkdir(&root.join("deps"))?;
+    // The entrypoint lives outside of `./src/` so it isn't compiled again as a project object
+    mkdir(&root.join("bins"))?;
+
+    let main = root.join("bins").join(main);
+    if !main.exists() {
+        println!("Writing {}", main.display());
+        fs::write(&main, lang.hello_world()).map_err(|e| KnobError::io(&main, e))?;
+    }
+
+    let gitignore = root.join(".gitignore");
+    if !gitignore.exists() {
+        println!("Writing {}", gitignore.display());
+        let ignored = format!(
+            "/build/\n/libdependencies.a\n/compile_commands.json\n/{}\n",
+            name
+        );
+        fs::write(&gitignore, ignored).map_err(|e| KnobError::io(&gitignore, e))?;
+    }
+
+    Ok(())
+}
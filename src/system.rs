@@ -0,0 +1,121 @@
+use crate::compileutils::*;
+use crate::config::*;
+use crate::errors::*;
+use crate::procutils::*;
+use crate::types::*;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// ! Resolves the `[System]` packages used on `os` with the pkg-config of its platform.
+/// ! pkg-config isn't needed by projects without any
+pub fn parse_system(
+    file: &Path,
+    os: &str,
+    platform: &PlatformConfig,
+    system: &BTreeMap<String, SystemConfig>,
+) -> KnobResult<Vec<SystemPackage>> {
+    let mut used = Vec::new();
+    for (name, config) in system {
+        if let Some(unknown) = config
+            .platforms
+            .iter()
+            .find(|p| !PLATFORMS.contains(&p.as_str()))
+        {
+            return Err(KnobError::InvalidValue {
+                file: file.to_path_buf(),
+                key: format!("System.{}.platforms", name),
+                message: format!(
+                    "unknown platform `{}`, expected one of {:?}",
+                    unknown, PLATFORMS
+                ),
+            });
+        }
+        if config.platforms.is_empty() || config.platforms.iter().any(|p| p == os) {
+            used.push((name, config));
+        }
+    }
+    if used.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let pkg_config = &platform.pkg_config;
+    if !bin_exists(pkg_config) {
+        return Err(KnobError::Config {
+            file: file.to_path_buf(),
+            message: format!(
+                "`{}` isn't installed or isn't on PATH, it resolves [System.{}]",
+                pkg_config, used[0].0
+            ),
+        });
+    }
+    let env = pkg_config_env(file, platform);
+
+    let mut packages = Vec::new();
+    for (name, config) in used {
+        let module = config.package.as_ref().unwrap_or(name);
+        let spec = match &config.version {
+            Some(version) => format!("{} {}", module, version),
+            None => module.clone(),
+        };
+        let query = |flags: &[&str]| {
+            let mut args: Vec<String> = flags.iter().map(|f| f.to_string()).collect();
+            args.push(spec.clone());
+            match execute_env(pkg_config, &args, &env, false, false) {
+                Ok(output) => Ok(output.split_whitespace().map(String::from).collect()),
+                Err(KnobError::Subprocess { message, .. }) => Err(KnobError::Config {
+                    file: file.to_path_buf(),
+                    message: format!(
+                        "[System.{}]: {} can't resolve `{}`: {}",
+                        name,
+                        pkg_config,
+                        spec,
+                        message.trim()
+                    ),
+                }),
+                Err(e) => Err(e),
+            }
+        };
+        let cflags = query(&["--cflags"])?;
+        let libs = match config.static_libs {
+            true => query(&["--libs", "--static"])?,
+            false => query(&["--libs"])?,
+        };
+        println!("System {}: {}", name, spec);
+        packages.push(SystemPackage {
+            name: name.clone(),
+            cflags,
+            libs,
+        });
+    }
+    Ok(packages)
+}
+
+/// ! `PKG_CONFIG_PATH` with the platform's directories first, and `PKG_CONFIG_SYSROOT_DIR` when
+/// ! the platform sets one. Both are otherwise inherited from knob's environment
+fn pkg_config_env(file: &Path, platform: &PlatformConfig) -> Vec<(String, String)> {
+    let project = file.parent().unwrap();
+    let mut env = Vec::new();
+    if !platform.pkg_config_path.is_empty() {
+        let mut paths: Vec<PathBuf> = platform
+            .pkg_config_path
+            .iter()
+            .map(|p| project.join(p))
+            .collect();
+        if let Some(inherited) = env::var_os("PKG_CONFIG_PATH") {
+            paths.extend(env::split_paths(&inherited));
+        }
+        let joined = env::join_paths(paths).unwrap_or_default();
+        env.push((
+            "PKG_CONFIG_PATH".to_string(),
+            joined.to_str().unwrap().to_string(),
+        ));
+    }
+    if let Some(sysroot) = &platform.pkg_config_sysroot {
+        env.push((
+            "PKG_CONFIG_SYSROOT_DIR".to_string(),
+            project.join(sysroot).to_str().unwrap().to_string(),
+        ));
+    }
+    env
+}
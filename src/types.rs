@@ -1,6 +1,9 @@
+use globset::GlobSet;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CompileCommands(pub Vec<CompileCommand>);
@@ -13,6 +16,15 @@ pub struct CompileCommand {
     pub output: String,
 }
 
+impl CompileCommand {
+    pub fn source(&self) -> PathBuf {
+        PathBuf::from(&self.file)
+    }
+    pub fn object(&self) -> PathBuf {
+        PathBuf::from(&self.output)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BuildShared {
     pub root: PathBuf,
@@ -20,23 +32,154 @@ pub struct BuildShared {
     pub build: String,
     pub headers: PathBuf,
     pub objs: Vec<PathBuf>,
+    /// ! Declared `Libraries`, see `libraries()` for the ones a driver installed
     pub libs: Vec<PathBuf>,
+    /// ! Names of the dependencies built before this one
+    pub requires: Vec<String>,
+    pub driver: Driver,
+    /// ! `<output>/<profile>/deps/<name>`, where drivers build and install the dependency
+    pub prefix: PathBuf,
 }
 
 impl BuildShared {
     pub fn is_loose(&self) -> bool {
-        self.libs.len() == 0
+        self.driver == Driver::Script && self.libs.is_empty()
+    }
+    /// ! The declared `Libraries`, or for a driver without any, the libraries found in the
+    /// ! `lib`/`lib64` of its prefix, or in its build directory when nothing was installed
+    pub fn libraries(&self) -> Vec<PathBuf> {
+        if self.driver == Driver::Script || !self.libs.is_empty() {
+            return self.libs.clone();
+        }
+        let installed = find_libraries(&self.prefix.join("lib"), 1)
+            .into_iter()
+            .chain(find_libraries(&self.prefix.join("lib64"), 1))
+            .collect::<Vec<_>>();
+        match installed.is_empty() {
+            true => find_libraries(&self.prefix.join("build"), usize::MAX),
+            false => installed,
+        }
     }
     pub fn name(&self) -> &str {
         self.root.file_name().unwrap().to_str().unwrap()
     }
 }
+/// ! Static and shared libraries under `dir`, sorted
+fn find_libraries(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let is_library = |name: &str| {
+        (name.starts_with("lib") && name.ends_with(".a"))
+            || name.ends_with(".so")
+            || name.contains(".so.")
+            || name.ends_with(".dylib")
+            || name.ends_with(".lib")
+    };
+    WalkDir::new(dir)
+        .max_depth(depth)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_type().is_dir())
+        .filter(|e| e.file_name().to_str().is_some_and(is_library))
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// ! How a dependency is built. `script` runs its `build`, the others generate the configure,
+/// ! build and install steps of the dependency's own build system
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    #[default]
+    Script,
+    Cmake,
+    Make,
+    Meson,
+    Autotools,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildProfile {
+    pub name: String,
+    pub compiler_args: Vec<String>,
+    pub linker_args: Vec<String>,
+}
+
+/// ! Languages of the sources knob compiles, each one can have its own toolchain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Language {
+    C,
+    Cpp,
+    ObjC,
+    ObjCpp,
+    Asm,
+}
+
+impl Language {
+    /// ! Routes a source file by its extension, `None` for anything that isn't compiled
+    pub fn from_path(path: &Path) -> Option<Language> {
+        match path.extension()?.to_str()? {
+            "c" => Some(Language::C),
+            "cpp" | "c++" | "cc" | "cxx" => Some(Language::Cpp),
+            "m" => Some(Language::ObjC),
+            "mm" => Some(Language::ObjCpp),
+            "s" | "S" => Some(Language::Asm),
+            _ => None,
+        }
+    }
+}
+
+/// ! Compiler and flags used for one `Language`
+#[derive(Debug, Clone)]
+pub struct BuildToolchain {
+    pub compiler: String,
+    pub compiler_args: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct BuildPlatform {
+    /// ! Key of the `[Platform.<os>]` in use, `linux`, `osx` or `win32`
+    pub name: String,
+    /// ! Used by languages that don't declare their own compiler
     pub compiler: String,
     pub linker: String,
+    pub archiver: String,
+    /// ! Passed to every language
     pub compiler_args: Vec<String>,
     pub linker_args: Vec<String>,
+    pub profile: BuildProfile,
+    pub toolchains: BTreeMap<Language, BuildToolchain>,
+    /// ! Archives are written without timestamps, uids or modes
+    pub reproducible: bool,
+    /// ! `[System]` packages of this platform, in the order of their names
+    pub system: Vec<SystemPackage>,
+}
+
+/// ! A system library resolved through pkg-config
+#[derive(Debug, Clone)]
+pub struct SystemPackage {
+    /// ! Key of the `[System]` table
+    pub name: String,
+    /// ! `pkg-config --cflags`
+    pub cflags: Vec<String>,
+    /// ! `pkg-config --libs`
+    pub libs: Vec<String>,
+}
+
+impl BuildPlatform {
+    /// ! Compiler and arguments for `source`, routed by its extension. The platform arguments
+    /// ! come first, then the profile's and finally the language's own
+    pub fn toolchain(&self, source: &Path) -> (String, Vec<String>) {
+        let mut args = self.compiler_args.clone();
+        args.append(&mut self.profile.compiler_args.clone());
+        let toolchain = Language::from_path(source).and_then(|l| self.toolchains.get(&l));
+        match toolchain {
+            Some(toolchain) => {
+                args.append(&mut toolchain.compiler_args.clone());
+                (toolchain.compiler.clone(), args)
+            }
+            None => (self.compiler.clone(), args),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -44,12 +187,128 @@ pub struct BuildDirs {
     pub dependencies: PathBuf,
     pub sources: PathBuf,
     pub output: PathBuf,
+    /// ! Objects of the selected profile, `<output>/<profile>`
+    pub objects: PathBuf,
+    /// ! Project `include`/`exclude` globs and the patterns of `<sources>/.knobignore`
+    pub filter: SourceFilter,
+    /// ! Absolute `include_dirs` of the project, in the order they were declared
+    pub includes: Vec<PathBuf>,
+    /// ! Whether header directories under `sources` are added to the include paths
+    pub discover_includes: bool,
+    pub ambiguous_headers: AmbiguousHeaders,
+}
+
+/// ! What to do when discovered headers share a file name, `#include "config.h"` would then
+/// ! resolve to whichever directory comes first
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousHeaders {
+    #[default]
+    Warn,
+    Error,
+}
+
+/// ! Include and exclude globs, matched against paths relative to the sources directory
+#[derive(Debug, Clone, Default)]
+pub struct SourceFilter {
+    /// ! `None` when no `include` is given, which includes everything
+    pub include: Option<GlobSet>,
+    pub exclude: GlobSet,
+}
+
+impl SourceFilter {
+    /// ! Excluded directories are skipped as a whole during discovery
+    pub fn excludes(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    /// ! A pattern matching a directory applies to everything under it
+    pub fn matches(&self, path: &Path) -> bool {
+        let mut ancestors = path.ancestors().filter(|a| !a.as_os_str().is_empty());
+        !ancestors.clone().any(|a| self.excludes(a))
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|i| ancestors.any(|a| i.is_match(a)))
+    }
+}
+
+/// ! Where a `[Dependency]` is fetched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchSource {
+    Git {
+        url: String,
+        /// ! Tag or commit, the default branch when `None`
+        reference: Option<String>,
+    },
+    Archive(PathBuf),
+    Path(PathBuf),
+}
+
+impl FetchSource {
+    /// ! One line summary, a dependency is fetched again when it changes
+    pub fn describe(&self) -> String {
+        match self {
+            FetchSource::Git {
+                url,
+                reference: Some(reference),
+            } => format!("git {} {}", url, reference),
+            FetchSource::Git {
+                url,
+                reference: None,
+            } => format!("git {}", url),
+            FetchSource::Archive(archive) => format!("archive {}", archive.display()),
+            FetchSource::Path(path) => format!("path {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchDependency {
+    pub name: String,
+    pub source: FetchSource,
+    pub manifest: Option<PathBuf>,
+}
+
+/// ! What a target produces from the project objects
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetKind {
+    #[default]
+    Executable,
+    Static,
+    Shared,
 }
+
 #[derive(Debug, Clone)]
 pub struct BuildTarget {
-    pub entrypoint: PathBuf,
+    /// ! Source with `main()`, optional for libraries
+    pub entrypoint: Option<PathBuf>,
     pub dependencies: Vec<String>,
     pub name: String,
+    /// ! Base name of the artifact, the target name unless `output_name` is set
+    pub output_name: String,
+    pub kind: TargetKind,
     pub compiler_args: Vec<String>,
     pub linker_args: Vec<String>,
+    /// ! Picks the project objects linked into this target
+    pub filter: SourceFilter,
+    /// ! Absolute `include_dirs` of the target
+    pub includes: Vec<PathBuf>,
+}
+
+impl BuildTarget {
+    /// ! File name of what the target produces on the host, `game`, `libengine.a`, `libengine.so`
+    pub fn artifact(&self) -> String {
+        let name = &self.output_name;
+        match self.kind {
+            TargetKind::Executable => name.clone(),
+            TargetKind::Static => format!("lib{}.a", name),
+            TargetKind::Shared => match std::env::consts::OS {
+                "macos" => format!("lib{}.dylib", name),
+                "windows" => format!("{}.dll", name),
+                _ => format!("lib{}.so", name),
+            },
+        }
+    }
 }